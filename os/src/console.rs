@@ -1,27 +1,102 @@
-//! SBI console driver, for text output
+//! Console, for text input and output
+//!
+//! The console goes through the SBI debug console until the UART is probed,
+//! and through the UART from then on.
 use core::fmt::{self, Write};
 
+use riscv::interrupt::supervisor as interrupt;
+use sbi_rt::Physical;
+
+use crate::{drivers::uart, mm::VirtAddr};
+
 struct Stdout;
 
 static CONSOLE_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+/// Run by [`getchar`] while it waits.
+static IDLE: spin::Once<fn()> = spin::Once::new();
 
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let _guard = CONSOLE_LOCK.lock();
-        for byte in s.bytes() {
-            let ret = sbi_rt::console_write_byte(byte);
-            if ret.is_err() {
-                return Err(fmt::Error);
-            }
+        // an interrupt handler printing on this hart would wait for the lock
+        // forever
+        interrupt::free(|| {
+            let _guard = CONSOLE_LOCK.lock();
+            write_unlocked(s)
+        })
+    }
+}
+
+/// Writes without taking the console lock.
+struct PanicStdout;
+
+impl Write for PanicStdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_unlocked(s)
+    }
+}
+
+fn write_unlocked(s: &str) -> fmt::Result {
+    if uart::ready() {
+        uart::write_bytes(s.as_bytes());
+        return Ok(());
+    }
+    // kernel memory is linearly mapped, so the physical address of the string
+    // is at hand
+    let mut bytes = s.as_bytes();
+    while !bytes.is_empty() {
+        let pa = VirtAddr::from_ptr(bytes.as_ptr()).to_phys().as_usize();
+        let ret = sbi_rt::console_write(Physical::new(bytes.len(), pa, 0));
+        if ret.is_err() {
+            return Err(fmt::Error);
         }
-        Ok(())
+        bytes = &bytes[ret.value.min(bytes.len())..];
     }
+    Ok(())
 }
 
 pub fn print(args: fmt::Arguments) {
     Stdout.write_fmt(args).unwrap();
 }
 
+/// Print without the console lock, for the panic handler: the panicking hart
+/// may hold it already. Output of other harts may interleave.
+pub fn print_unlocked(args: fmt::Arguments) {
+    let _ = PanicStdout.write_fmt(args);
+}
+
+/// Read a byte if one is waiting.
+pub fn try_getchar() -> Option<u8> {
+    if uart::ready() {
+        return uart::read_byte();
+    }
+    let mut byte = [0u8];
+    let pa = VirtAddr::from_ptr(byte.as_mut_ptr()).to_phys().as_usize();
+    let ret = sbi_rt::console_read(Physical::new(1, pa, 0));
+    (ret.is_ok() && ret.value == 1).then_some(byte[0])
+}
+
+/// Have [`getchar`] run `idle` while it waits for input.
+pub fn set_idle(idle: fn()) {
+    IDLE.call_once(|| idle);
+}
+
+/// Wait for a byte.
+pub fn getchar() -> u8 {
+    loop {
+        if let Some(byte) = try_getchar() {
+            return byte;
+        }
+        if let Some(idle) = IDLE.get() {
+            idle();
+        }
+        if uart::has_interrupt() {
+            riscv::asm::wfi();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
 /// Print! to the host console using the format string and arguments.
 #[macro_export]
 macro_rules! print {
@@ -0,0 +1,144 @@
+//! Kernel log ring buffer
+//!
+//! Every log record is also kept here, so that it can be read back later: by
+//! the `dmesg` shell command, after a panic, or by `syslog` once there is user
+//! space. The buffer is a ring of fixed-size slots, one record each, the
+//! oldest overwritten first.
+//!
+//! Writers never wait: a writer takes the next sequence number and owns the
+//! slot it falls in, marking the slot odd while it writes and even once it is
+//! done. A reader copies a slot out and keeps the copy only if the slot was
+//! even and unchanged before and after, so records being written or
+//! overwritten meanwhile are skipped rather than torn.
+
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+const SLOTS: usize = 512;
+/// Bytes of text a slot holds; longer records are cut.
+const TEXT_SIZE: usize = 240;
+
+struct Slot {
+    /// `2 * seq + 1` while record `seq` is written, `2 * seq + 2` once done
+    stamp: AtomicUsize,
+    len: AtomicUsize,
+    text: [AtomicU8; TEXT_SIZE],
+}
+
+impl Slot {
+    const fn new() -> Slot {
+        Slot {
+            stamp: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            text: [const { AtomicU8::new(0) }; TEXT_SIZE],
+        }
+    }
+}
+
+static RING: [Slot; SLOTS] = [const { Slot::new() }; SLOTS];
+/// Sequence number of the next record.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+/// Records before this one were cleared.
+static FIRST: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes into a slot, dropping what does not fit.
+struct SlotWriter<'a> {
+    slot: &'a Slot,
+    len: usize,
+}
+
+impl Write for SlotWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes().iter().take(TEXT_SIZE - self.len) {
+            self.slot.text[self.len].store(byte, Ordering::Relaxed);
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Append a record.
+pub fn write(args: fmt::Arguments) {
+    let seq = NEXT.fetch_add(1, Ordering::Relaxed);
+    let slot = &RING[seq % SLOTS];
+    slot.stamp.store(2 * seq + 1, Ordering::Relaxed);
+    // the stamp is odd before any byte changes
+    core::sync::atomic::fence(Ordering::Release);
+    let mut writer = SlotWriter { slot, len: 0 };
+    let _ = writer.write_fmt(args);
+    slot.len.store(writer.len, Ordering::Relaxed);
+    slot.stamp.store(2 * seq + 2, Ordering::Release);
+}
+
+/// Copy record `seq` into `buf`, returning its length, unless it is being
+/// written or was overwritten.
+fn read_record(seq: usize, buf: &mut [u8; TEXT_SIZE]) -> Option<usize> {
+    let slot = &RING[seq % SLOTS];
+    let stamp = 2 * seq + 2;
+    if slot.stamp.load(Ordering::Acquire) != stamp {
+        return None;
+    }
+    let len = slot.len.load(Ordering::Relaxed).min(TEXT_SIZE);
+    for (byte, text) in buf.iter_mut().zip(&slot.text).take(len) {
+        *byte = text.load(Ordering::Relaxed);
+    }
+    // the copy is good if no writer took the slot meanwhile
+    core::sync::atomic::fence(Ordering::Acquire);
+    (slot.stamp.load(Ordering::Relaxed) == stamp).then_some(len)
+}
+
+/// Call `f` with every record still in the buffer, oldest first, along with
+/// its sequence number.
+pub fn for_each(mut f: impl FnMut(usize, &str)) {
+    let next = NEXT.load(Ordering::Acquire);
+    let first = FIRST
+        .load(Ordering::Relaxed)
+        .max(next.saturating_sub(SLOTS));
+    let mut buf = [0; TEXT_SIZE];
+    for seq in first..next {
+        if let Some(len) = read_record(seq, &mut buf) {
+            // a cut record may end inside a character
+            let text = match core::str::from_utf8(&buf[..len]) {
+                Ok(text) => text,
+                Err(e) => core::str::from_utf8(&buf[..e.valid_up_to()]).unwrap(),
+            };
+            f(seq, text);
+        }
+    }
+}
+
+/// Copy the newest records that fit into `buf`, one per line, oldest first.
+/// Returns the number of bytes written. This is what `syslog` reads.
+#[allow(dead_code)] // until there is a `syslog` system call
+pub fn read(buf: &mut [u8]) -> usize {
+    let mut total = 0;
+    for_each(|_, text| total += text.len() + 1);
+    // skip the oldest records until the rest fit
+    let mut skip = total.saturating_sub(buf.len());
+    let mut written = 0;
+    for_each(|_, text| {
+        let line = text.len() + 1;
+        if skip > 0 {
+            skip = skip.saturating_sub(line);
+            return;
+        }
+        if written + line <= buf.len() {
+            buf[written..written + text.len()].copy_from_slice(text.as_bytes());
+            buf[written + text.len()] = b'\n';
+            written += line;
+        }
+    });
+    written
+}
+
+/// Forget every record written so far.
+pub fn clear() {
+    FIRST.store(NEXT.load(Ordering::Acquire), Ordering::Relaxed);
+}
+
+/// Print every record to the console.
+pub fn dump() {
+    for_each(|_, text| println!("{}", text));
+}
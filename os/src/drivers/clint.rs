@@ -0,0 +1,44 @@
+//! Core-Local Interruptor, `riscv,clint0`
+//!
+//! The CLINT is a machine-mode device, guarded by PMP from the kernel: timers
+//! and IPIs go through SBI instead. The driver only records where it is.
+
+use linkme::distributed_slice;
+use spin::Once;
+
+use super::{Driver, ProbeError, DRIVERS};
+use crate::dt;
+
+/// Physical `[start, end)` of the CLINT registers.
+static CLINT: Once<(usize, usize)> = Once::new();
+
+struct ClintDriver;
+
+#[distributed_slice(DRIVERS)]
+static CLINT_DRIVER: &dyn Driver = &ClintDriver;
+
+impl Driver for ClintDriver {
+    fn name(&self) -> &'static str {
+        "clint"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["riscv,clint0", "sifive,clint0"]
+    }
+
+    fn is_interrupt_controller(&self) -> bool {
+        true
+    }
+
+    fn probe(&self, node: dt::Node<'static>) -> Result<(), ProbeError> {
+        if CLINT.is_completed() {
+            return Err(ProbeError::AlreadyProbed);
+        }
+        let &reg = node
+            .reg()
+            .first()
+            .ok_or(ProbeError::MissingProperty("reg"))?;
+        CLINT.call_once(|| reg);
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! Device drivers
+//!
+//! Every driver puts itself in [`DRIVERS`] and is probed with each enabled
+//! device tree node listing one of its [`Driver::compatible`] strings.
+//! Interrupt controllers are probed first, so that the devices probed after
+//! them can register their interrupts.
+
+use alloc::{collections::BTreeSet, string::String};
+use core::{fmt, ptr};
+
+use linkme::distributed_slice;
+use log::*;
+
+use crate::dt::{self, DeviceTree};
+
+pub mod clint;
+pub mod plic;
+pub mod rtc;
+pub mod syscon;
+pub mod uart;
+pub mod virtio;
+
+pub trait Driver: Sync {
+    /// Name shown in the log.
+    fn name(&self) -> &'static str;
+
+    /// `compatible` strings of the nodes this driver handles.
+    fn compatible(&self) -> &'static [&'static str];
+
+    /// Whether this driver is an interrupt controller other drivers depend on.
+    fn is_interrupt_controller(&self) -> bool {
+        false
+    }
+
+    /// Bring up the device `node` describes.
+    fn probe(&self, node: dt::Node<'static>) -> Result<(), ProbeError>;
+}
+
+#[derive(Debug)]
+pub enum ProbeError {
+    /// The node lacks a property the driver needs, or has a malformed one.
+    MissingProperty(&'static str),
+    /// Nothing answers at the address of the node.
+    NoDevice,
+    /// The driver handles a single device and already has one.
+    AlreadyProbed,
+    Other(String),
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::MissingProperty(name) => write!(f, "missing or malformed {name:?}"),
+            ProbeError::NoDevice => write!(f, "no device"),
+            ProbeError::AlreadyProbed => write!(f, "already probed"),
+            ProbeError::Other(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+/// Every driver linked into the kernel.
+#[distributed_slice]
+pub static DRIVERS: [&'static dyn Driver];
+
+/// The driver for `node`: the first one matching the most specific of its
+/// `compatible` strings.
+fn driver_for(node: &dt::Node<'_>) -> Option<&'static dyn Driver> {
+    node.compatible().find_map(|compatible| {
+        DRIVERS
+            .iter()
+            .copied()
+            .find(|driver| driver.compatible().contains(&compatible))
+    })
+}
+
+/// Probe every enabled node of `tree` with its driver, and log the nodes no
+/// driver handles.
+pub fn probe_all(tree: &'static DeviceTree) {
+    let mut probed = BTreeSet::new();
+    for interrupt_controllers in [true, false] {
+        let drivers = DRIVERS
+            .iter()
+            .copied()
+            .filter(|driver| driver.is_interrupt_controller() == interrupt_controllers);
+        for driver in drivers {
+            for &compatible in driver.compatible() {
+                for node in tree.find_compatible(compatible) {
+                    // a more specific string of the node may belong to another
+                    // driver, and a node may list several strings of this one
+                    let ours = driver_for(&node).is_some_and(|d| ptr::addr_eq(d, driver));
+                    if !ours || !probed.insert(node.id()) {
+                        continue;
+                    }
+                    match driver.probe(node) {
+                        Ok(()) => info!("[kernel] {:?}: probed by {}", node, driver.name()),
+                        Err(ProbeError::NoDevice) => debug!("[kernel] {node:?}: no device"),
+                        Err(e) => warn!(
+                            "[kernel] {:?}: {} failed to probe: {}",
+                            node,
+                            driver.name(),
+                            e
+                        ),
+                    }
+                }
+            }
+        }
+    }
+    for node in tree.nodes().filter(|node| node.is_enabled()) {
+        if node.property("compatible").is_some() && driver_for(&node).is_none() {
+            let compatible = node.compatible().next().unwrap_or("");
+            info!("[kernel] {node:?}: no driver for {compatible:?}");
+        }
+    }
+}
+
+/// Map the first `reg` region of `node` and return its virtual address.
+pub fn map_reg(node: &dt::Node<'_>) -> Result<usize, ProbeError> {
+    let &(start, end) = node
+        .reg()
+        .first()
+        .ok_or(ProbeError::MissingProperty("reg"))?;
+    Ok(crate::map_mmio(start, end - start).as_usize())
+}
+
+/// The first source number in `interrupts` of `node`.
+pub fn irq_of(node: &dt::Node<'_>) -> Option<u32> {
+    node.interrupts()
+        .first()
+        .and_then(|spec| spec.first().copied())
+}
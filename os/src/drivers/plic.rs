@@ -0,0 +1,245 @@
+//! Platform-Level Interrupt Controller, `riscv,plic0`
+//!
+//! Every hart takes external interrupts through its supervisor-mode context.
+//! Sources registered with [`register`] are enabled in the context of every
+//! hart, so whichever hart claims one first runs its handler.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use core::ptr::{read_volatile, write_volatile};
+
+use linkme::distributed_slice;
+use log::*;
+use riscv::{interrupt::supervisor as interrupt, register::sie};
+use spin::{Mutex, Once};
+
+use super::{Driver, ProbeError, DRIVERS};
+use crate::{dt, percpu};
+
+const PRIORITY_BASE: usize = 0x0;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// `interrupts-extended` cause of a supervisor external interrupt
+pub const SUPERVISOR_EXTERNAL: u32 = 9;
+
+/// Highest priority a source can have; 0 means the source never interrupts.
+pub const MAX_PRIORITY: u32 = 7;
+
+type Handler = Arc<dyn Fn() + Send + Sync>;
+
+pub struct Plic {
+    base: usize,
+    /// number of interrupt sources, `riscv,ndev`; source 0 does not exist
+    ndev: u32,
+    /// supervisor context of each hart, as `(hartid, context)`
+    contexts: Vec<(usize, usize)>,
+    handlers: Mutex<Vec<Option<Handler>>>,
+    /// held while changing an enable word, which harts share
+    enable_lock: Mutex<()>,
+}
+
+static PLIC: Once<Plic> = Once::new();
+
+impl Plic {
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    fn context_of(&self, hartid: usize) -> Option<usize> {
+        self.contexts
+            .iter()
+            .find(|&&(id, _)| id == hartid)
+            .map(|&(_, context)| context)
+    }
+
+    fn set_priority(&self, irq: u32, priority: u32) {
+        assert!(irq != 0 && irq <= self.ndev, "no interrupt source {irq}");
+        unsafe { write_volatile(self.reg(PRIORITY_BASE + 4 * irq as usize), priority) };
+    }
+
+    /// Interrupts of priority up to `threshold` are masked on `hartid`.
+    fn set_threshold(&self, hartid: usize, threshold: u32) {
+        if let Some(context) = self.context_of(hartid) {
+            let offset = CONTEXT_BASE + CONTEXT_STRIDE * context + CONTEXT_THRESHOLD;
+            unsafe { write_volatile(self.reg(offset), threshold) };
+        }
+    }
+
+    fn set_enable(&self, context: usize, irq: u32, enable: bool) {
+        let offset = ENABLE_BASE + ENABLE_STRIDE * context + 4 * (irq as usize / 32);
+        let bit = 1 << (irq % 32);
+        interrupt::free(|| {
+            let _guard = self.enable_lock.lock();
+            unsafe {
+                let word = read_volatile(self.reg(offset));
+                let word = if enable { word | bit } else { word & !bit };
+                write_volatile(self.reg(offset), word);
+            }
+        });
+    }
+
+    fn claim(&self, context: usize) -> u32 {
+        let offset = CONTEXT_BASE + CONTEXT_STRIDE * context + CONTEXT_CLAIM;
+        unsafe { read_volatile(self.reg(offset)) }
+    }
+
+    fn complete(&self, context: usize, irq: u32) {
+        let offset = CONTEXT_BASE + CONTEXT_STRIDE * context + CONTEXT_CLAIM;
+        unsafe { write_volatile(self.reg(offset), irq) };
+    }
+}
+
+/// Set up the PLIC whose registers are mapped at `base`. `contexts` pairs every
+/// hart with its supervisor context.
+pub fn init(base: usize, ndev: u32, contexts: Vec<(usize, usize)>) {
+    let plic = PLIC.call_once(|| Plic {
+        base,
+        ndev,
+        contexts,
+        handlers: Mutex::new(vec![None; ndev as usize + 1]),
+        enable_lock: Mutex::new(()),
+    });
+    for irq in 1..=ndev {
+        plic.set_priority(irq, 0);
+    }
+    for &(_, context) in &plic.contexts {
+        for irq in 1..=ndev {
+            plic.set_enable(context, irq, false);
+        }
+    }
+    info!(
+        "[kernel] plic at {:#x}, {} sources, {} harts",
+        base,
+        ndev,
+        plic.contexts.len()
+    );
+}
+
+struct PlicDriver;
+
+#[distributed_slice(DRIVERS)]
+static PLIC_DRIVER: &dyn Driver = &PlicDriver;
+
+impl Driver for PlicDriver {
+    fn name(&self) -> &'static str {
+        "plic"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["riscv,plic0", "sifive,plic-1.0.0"]
+    }
+
+    fn is_interrupt_controller(&self) -> bool {
+        true
+    }
+
+    fn probe(&self, node: dt::Node<'static>) -> Result<(), ProbeError> {
+        if PLIC.is_completed() {
+            return Err(ProbeError::AlreadyProbed);
+        }
+        let ndev = node
+            .property("riscv,ndev")
+            .and_then(dt::Property::as_u32)
+            .ok_or(ProbeError::MissingProperty("riscv,ndev"))?;
+        // context `i` is the `i`th entry of `interrupts-extended`, and goes to
+        // the local interrupt controller of a cpu node
+        let contexts = node
+            .interrupts_extended()
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, specifier))| specifier.first() == Some(&SUPERVISOR_EXTERNAL))
+            .filter_map(|(context, (intc, _))| {
+                let &(hartid, _) = intc.parent()?.reg().first()?;
+                Some((hartid, context))
+            })
+            .collect();
+        init(super::map_reg(&node)?, ndev, contexts);
+        init_hart();
+        Ok(())
+    }
+}
+
+/// Whether a PLIC was found, so that interrupts can be registered.
+pub fn ready() -> bool {
+    PLIC.is_completed()
+}
+
+/// Take external interrupts on the calling hart.
+pub fn init_hart() {
+    let Some(plic) = PLIC.get() else {
+        return;
+    };
+    let hartid = percpu::hart_id();
+    if plic.context_of(hartid).is_none() {
+        warn!("[kernel] hart {hartid} has no PLIC context");
+        return;
+    }
+    set_threshold(hartid, 0);
+    unsafe { sie::set_sext() };
+}
+
+/// Run `handler` whenever source `irq` raises an interrupt, and enable the
+/// source on every hart at `priority`, from 1 to [`MAX_PRIORITY`].
+pub fn register(irq: u32, priority: u32, handler: impl Fn() + Send + Sync + 'static) {
+    let plic = PLIC.get().expect("PLIC not initialized");
+    assert!(irq != 0 && irq <= plic.ndev, "no interrupt source {irq}");
+    let handler: Handler = Arc::new(handler);
+    interrupt::free(|| plic.handlers.lock()[irq as usize] = Some(handler));
+    set_priority(irq, priority.max(1));
+    for &(_, context) in &plic.contexts {
+        plic.set_enable(context, irq, true);
+    }
+}
+
+/// Disable source `irq` and drop its handler.
+#[allow(dead_code)] // every driver so far keeps its device until shutdown
+pub fn unregister(irq: u32) {
+    let plic = PLIC.get().expect("PLIC not initialized");
+    for &(_, context) in &plic.contexts {
+        plic.set_enable(context, irq, false);
+    }
+    plic.set_priority(irq, 0);
+    interrupt::free(|| plic.handlers.lock()[irq as usize] = None);
+}
+
+/// Set the priority of source `irq`, capped at [`MAX_PRIORITY`].
+pub fn set_priority(irq: u32, priority: u32) {
+    PLIC.get()
+        .expect("PLIC not initialized")
+        .set_priority(irq, priority.min(MAX_PRIORITY));
+}
+
+/// Mask interrupts of priority up to `threshold` on `hartid`.
+pub fn set_threshold(hartid: usize, threshold: u32) {
+    PLIC.get()
+        .expect("PLIC not initialized")
+        .set_threshold(hartid, threshold);
+}
+
+/// Supervisor external interrupt: claim every pending source and run its
+/// handler.
+pub fn handle_interrupt() {
+    let Some(plic) = PLIC.get() else {
+        return;
+    };
+    let Some(context) = plic.context_of(percpu::hart_id()) else {
+        return;
+    };
+    loop {
+        let irq = plic.claim(context);
+        if irq == 0 {
+            break;
+        }
+        // the lock is not held while the handler runs
+        let handler = plic.handlers.lock().get(irq as usize).cloned().flatten();
+        match handler {
+            Some(handler) => handler(),
+            None => warn!("[kernel] spurious external interrupt {irq}"),
+        }
+        plic.complete(context, irq);
+    }
+}
@@ -0,0 +1,64 @@
+//! Goldfish real-time clock, `google,goldfish-rtc`
+
+use core::ptr::read_volatile;
+
+use linkme::distributed_slice;
+use log::*;
+use spin::Once;
+
+use super::{Driver, ProbeError, DRIVERS};
+use crate::{dt, time::Duration};
+
+/// Nanoseconds since the Unix epoch, low half; reading it latches the high half.
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+struct Rtc {
+    base: usize,
+}
+
+static RTC: Once<Rtc> = Once::new();
+
+impl Rtc {
+    fn now(&self) -> Duration {
+        let (low, high) = unsafe {
+            let low = read_volatile((self.base + TIME_LOW) as *const u32);
+            let high = read_volatile((self.base + TIME_HIGH) as *const u32);
+            (low, high)
+        };
+        Duration::from_nanos((high as u64) << 32 | low as u64)
+    }
+}
+
+struct RtcDriver;
+
+#[distributed_slice(DRIVERS)]
+static RTC_DRIVER: &dyn Driver = &RtcDriver;
+
+impl Driver for RtcDriver {
+    fn name(&self) -> &'static str {
+        "goldfish-rtc"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["google,goldfish-rtc"]
+    }
+
+    fn probe(&self, node: dt::Node<'static>) -> Result<(), ProbeError> {
+        if RTC.is_completed() {
+            return Err(ProbeError::AlreadyProbed);
+        }
+        let base = super::map_reg(&node)?;
+        let rtc = RTC.call_once(|| Rtc { base });
+        info!(
+            "[kernel] wall clock at {} s since epoch",
+            rtc.now().as_secs()
+        );
+        Ok(())
+    }
+}
+
+/// Wall-clock time since the Unix epoch, if there is a real-time clock.
+pub fn now() -> Option<Duration> {
+    RTC.get().map(Rtc::now)
+}
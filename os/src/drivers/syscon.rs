@@ -0,0 +1,118 @@
+//! System controller registers, `syscon`, and the `syscon-poweroff` and
+//! `syscon-reboot` nodes that write a value to one of them
+//!
+//! On QEMU the syscon is the `sifive,test0` device, which powers the machine
+//! off or resets it.
+
+use core::ptr::write_volatile;
+
+use linkme::distributed_slice;
+use spin::Once;
+
+use super::{Driver, ProbeError, DRIVERS};
+use crate::dt;
+
+/// A register write that powers off or resets the machine.
+#[derive(Clone, Copy)]
+struct Action {
+    /// virtual address of the register
+    reg: usize,
+    value: u32,
+}
+
+static POWEROFF: Once<Action> = Once::new();
+static REBOOT: Once<Action> = Once::new();
+
+struct SysconDriver;
+struct PoweroffDriver;
+struct RebootDriver;
+
+#[distributed_slice(DRIVERS)]
+static SYSCON_DRIVER: &dyn Driver = &SysconDriver;
+#[distributed_slice(DRIVERS)]
+static POWEROFF_DRIVER: &dyn Driver = &PoweroffDriver;
+#[distributed_slice(DRIVERS)]
+static REBOOT_DRIVER: &dyn Driver = &RebootDriver;
+
+impl Driver for SysconDriver {
+    fn name(&self) -> &'static str {
+        "syscon"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["syscon", "sifive,test0", "sifive,test1"]
+    }
+
+    fn probe(&self, node: dt::Node<'static>) -> Result<(), ProbeError> {
+        super::map_reg(&node).map(|_| ())
+    }
+}
+
+/// Read the `regmap`, `offset` and `value` of a `syscon-poweroff` or
+/// `syscon-reboot` node.
+fn action_of(node: &dt::Node<'_>) -> Result<Action, ProbeError> {
+    let u32_property = |name| {
+        node.property(name)
+            .and_then(dt::Property::as_u32)
+            .ok_or(ProbeError::MissingProperty(name))
+    };
+    let regmap = node
+        .property("regmap")
+        .and_then(dt::Property::as_u32)
+        .and_then(|phandle| dt::tree().find_by_phandle(phandle))
+        .ok_or(ProbeError::MissingProperty("regmap"))?;
+    let offset = u32_property("offset")?;
+    let value = u32_property("value")?;
+    Ok(Action {
+        reg: super::map_reg(&regmap)? + offset as usize,
+        value,
+    })
+}
+
+impl Driver for PoweroffDriver {
+    fn name(&self) -> &'static str {
+        "syscon-poweroff"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["syscon-poweroff"]
+    }
+
+    fn probe(&self, node: dt::Node<'static>) -> Result<(), ProbeError> {
+        let action = action_of(&node)?;
+        POWEROFF.call_once(|| action);
+        Ok(())
+    }
+}
+
+impl Driver for RebootDriver {
+    fn name(&self) -> &'static str {
+        "syscon-reboot"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["syscon-reboot"]
+    }
+
+    fn probe(&self, node: dt::Node<'static>) -> Result<(), ProbeError> {
+        let action = action_of(&node)?;
+        REBOOT.call_once(|| action);
+        Ok(())
+    }
+}
+
+fn perform(action: Option<&Action>) {
+    if let Some(action) = action {
+        unsafe { write_volatile(action.reg as *mut u32, action.value) };
+    }
+}
+
+/// Power the machine off, if the device tree says how. Returns otherwise.
+pub fn poweroff() {
+    perform(POWEROFF.get());
+}
+
+/// Reset the machine, if the device tree says how. Returns otherwise.
+pub fn reboot() {
+    perform(REBOOT.get());
+}
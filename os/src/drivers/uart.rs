@@ -0,0 +1,190 @@
+//! NS16550-compatible UART, `ns16550a`
+//!
+//! Received bytes are moved into a ring buffer by the interrupt handler, and
+//! also whenever the buffer is read, so that input still arrives on a UART
+//! without an interrupt or while interrupts are disabled.
+
+use linkme::distributed_slice;
+use riscv::interrupt::supervisor as interrupt;
+use spin::{Mutex, Once};
+use uart16550::{InterruptTypes, LineControl, ModemControl, TriggerLevel, Uart16550};
+
+use super::{Driver, ProbeError, DRIVERS};
+use crate::dt;
+
+const BAUD_RATE: u32 = 115_200;
+const RX_BUFFER_SIZE: usize = 1024;
+/// `OUT2` gates the interrupt line on PC-style boards, `RTS` and `DTR` tell the
+/// other end we are ready.
+const MODEM_CONTROL: u8 = 0b1011;
+/// Above the disks, so that typing stays responsive during heavy I/O.
+const IRQ_PRIORITY: u32 = 2;
+
+/// The registers, 8 or 32 bits wide depending on `reg-io-width`.
+enum Regs {
+    Byte(&'static Uart16550<u8>),
+    Word(&'static Uart16550<u32>),
+}
+
+macro_rules! with_regs {
+    ($regs:expr, $uart:ident => $body:expr) => {
+        match $regs {
+            Regs::Byte($uart) => $body,
+            Regs::Word($uart) => $body,
+        }
+    };
+}
+
+struct Uart {
+    regs: Regs,
+    /// interrupt source on the PLIC
+    irq: Option<u32>,
+}
+
+// the registers are only written under the console lock or with the RX buffer
+// locked
+unsafe impl Send for Uart {}
+unsafe impl Sync for Uart {}
+
+struct RingBuffer {
+    buf: [u8; RX_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> RingBuffer {
+        RingBuffer {
+            buf: [0; RX_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Append `byte`, dropping it when the buffer is full.
+    fn push(&mut self, byte: u8) {
+        if self.len < RX_BUFFER_SIZE {
+            self.buf[(self.head + self.len) % RX_BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % RX_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+static UART: Once<Uart> = Once::new();
+static RX: Mutex<RingBuffer> = Mutex::new(RingBuffer::new());
+
+impl Uart {
+    fn init(&self, divisor: Option<u16>) {
+        with_regs!(&self.regs, uart => {
+            uart.ier().write(InterruptTypes::ZERO);
+            if let Some(divisor) = divisor {
+                uart.write_divisor(divisor);
+            }
+            uart.lcr().write(LineControl::CONFIG_8N1);
+            uart.iir_fcr().write(TriggerLevel::_1.and_reset());
+            uart.mcr().write(ModemControl(MODEM_CONTROL));
+            if self.irq.is_some() {
+                uart.ier().write(InterruptTypes::ZERO.enable_rda());
+            }
+        })
+    }
+
+    fn write_byte(&self, byte: u8) {
+        with_regs!(&self.regs, uart => {
+            while uart.write(&[byte]) == 0 {
+                core::hint::spin_loop();
+            }
+        })
+    }
+
+    /// Move whatever the receiver holds into the ring buffer.
+    fn drain(&self, rx: &mut RingBuffer) {
+        let mut byte = [0];
+        while with_regs!(&self.regs, uart => uart.read(&mut byte)) != 0 {
+            rx.push(byte[0]);
+        }
+    }
+}
+
+struct UartDriver;
+
+#[distributed_slice(DRIVERS)]
+static UART_DRIVER: &dyn Driver = &UartDriver;
+
+impl Driver for UartDriver {
+    fn name(&self) -> &'static str {
+        "ns16550"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["ns16550a", "ns16550"]
+    }
+
+    fn probe(&self, node: dt::Node<'static>) -> Result<(), ProbeError> {
+        if UART.is_completed() {
+            return Err(ProbeError::AlreadyProbed);
+        }
+        let u32_property = |name| node.property(name).and_then(dt::Property::as_u32);
+        let base = super::map_reg(&node)?;
+        // registers are `1 << reg-shift` bytes apart
+        let regs = match (u32_property("reg-shift"), u32_property("reg-io-width")) {
+            (None | Some(0), None | Some(1)) => Regs::Byte(unsafe { &*(base as *const _) }),
+            (Some(2), None | Some(4)) => Regs::Word(unsafe { &*(base as *const _) }),
+            _ => return Err(ProbeError::MissingProperty("reg-shift")),
+        };
+        let divisor = u32_property("clock-frequency")
+            .filter(|&clock| clock != 0)
+            .map(|clock| (clock / (16 * BAUD_RATE)).clamp(1, u16::MAX as u32) as u16);
+        let irq = super::irq_of(&node).filter(|_| super::plic::ready());
+        let uart = UART.call_once(|| Uart { regs, irq });
+        uart.init(divisor);
+        if let Some(irq) = irq {
+            super::plic::register(irq, IRQ_PRIORITY, handle_interrupt);
+        }
+        Ok(())
+    }
+}
+
+/// Whether output can go to the UART.
+pub fn ready() -> bool {
+    UART.is_completed()
+}
+
+/// Write `bytes`, waiting for room in the transmitter. The caller serializes
+/// writers.
+pub fn write_bytes(bytes: &[u8]) {
+    if let Some(uart) = UART.get() {
+        bytes.iter().for_each(|&byte| uart.write_byte(byte));
+    }
+}
+
+/// The oldest byte received and not read yet.
+pub fn read_byte() -> Option<u8> {
+    let uart = UART.get()?;
+    interrupt::free(|| {
+        let mut rx = RX.lock();
+        uart.drain(&mut rx);
+        rx.pop()
+    })
+}
+
+/// Whether received bytes wake the hart from `wfi`.
+pub fn has_interrupt() -> bool {
+    UART.get().is_some_and(|uart| uart.irq.is_some())
+}
+
+fn handle_interrupt() {
+    if let Some(uart) = UART.get() {
+        uart.drain(&mut RX.lock());
+    }
+}
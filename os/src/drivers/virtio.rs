@@ -0,0 +1,75 @@
+//! VirtIO over MMIO, `virtio,mmio`
+//!
+//! Probing only identifies the device behind each transport. Device drivers
+//! claim the transports of their type with [`take`].
+
+use alloc::vec::Vec;
+use core::ptr::NonNull;
+
+use linkme::distributed_slice;
+use log::*;
+use spin::Mutex;
+use virtio_drivers::transport::{
+    mmio::{MmioError, MmioTransport, VirtIOHeader},
+    DeviceType, Transport,
+};
+
+use super::{Driver, ProbeError, DRIVERS};
+use crate::dt;
+
+/// A transport with a device behind it, not claimed by a driver yet.
+pub struct VirtioDevice {
+    pub node: dt::Node<'static>,
+    pub irq: Option<u32>,
+    pub transport: MmioTransport,
+}
+
+static DEVICES: Mutex<Vec<VirtioDevice>> = Mutex::new(Vec::new());
+
+struct VirtioMmioDriver;
+
+#[distributed_slice(DRIVERS)]
+static VIRTIO_MMIO_DRIVER: &dyn Driver = &VirtioMmioDriver;
+
+impl Driver for VirtioMmioDriver {
+    fn name(&self) -> &'static str {
+        "virtio-mmio"
+    }
+
+    fn compatible(&self) -> &'static [&'static str] {
+        &["virtio,mmio"]
+    }
+
+    fn probe(&self, node: dt::Node<'static>) -> Result<(), ProbeError> {
+        let base = super::map_reg(&node)?;
+        let header = NonNull::new(base as *mut VirtIOHeader).ok_or(ProbeError::NoDevice)?;
+        let transport = match unsafe { MmioTransport::new(header) } {
+            Ok(transport) => transport,
+            // QEMU lists every slot, most of them empty
+            Err(MmioError::ZeroDeviceId) => return Err(ProbeError::NoDevice),
+            Err(e) => return Err(ProbeError::Other(alloc::format!("{e:?}"))),
+        };
+        debug!(
+            "[kernel] {:?}: virtio {:?} device, version {:?}",
+            node,
+            transport.device_type(),
+            transport.version()
+        );
+        DEVICES.lock().push(VirtioDevice {
+            node,
+            irq: super::irq_of(&node),
+            transport,
+        });
+        Ok(())
+    }
+}
+
+/// Claim every probed device of type `device_type`.
+pub fn take(device_type: DeviceType) -> Vec<VirtioDevice> {
+    let mut devices = DEVICES.lock();
+    let (taken, rest) = devices
+        .drain(..)
+        .partition(|device| device.transport.device_type() == device_type);
+    *devices = rest;
+    taken
+}
@@ -0,0 +1,496 @@
+//! Device tree, parsed from the flattened blob into allocated nodes
+//!
+//! [`init`] parses the blob the firmware hands over, after which drivers look
+//! themselves up with [`tree`], for instance through
+//! [`DeviceTree::find_compatible`].
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{fmt, slice, str};
+
+use spin::Once;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+/// Size of the header up to `size_dt_struct`, the last field read.
+const HEADER_SIZE: usize = 40;
+/// Oldest version whose layout this parser understands.
+const LAST_COMP_VERSION: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+    /// The blob ends before a structure at this offset does.
+    Truncated(usize),
+    BadToken {
+        offset: usize,
+        token: u32,
+    },
+    /// A node or property name is not UTF-8.
+    BadName(usize),
+}
+
+/// Index of a node in [`DeviceTree`].
+pub type NodeId = usize;
+
+pub struct DeviceTree {
+    /// every node, the root first and parents before their children
+    nodes: Vec<NodeData>,
+    phandles: BTreeMap<u32, NodeId>,
+    /// `/memreserve/` entries, as `[start, end)`
+    reserved: Vec<(usize, usize)>,
+    total_size: usize,
+}
+
+struct NodeData {
+    name: String,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    properties: Vec<Property>,
+}
+
+pub struct Property {
+    name: String,
+    value: Vec<u8>,
+}
+
+/// A node, borrowed from its tree so that it can reach its relatives.
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    tree: &'a DeviceTree,
+    id: NodeId,
+}
+
+static DEVICE_TREE: Once<DeviceTree> = Once::new();
+
+/// Parse the blob at `ptr`, which must stay mapped for the duration of the
+/// call only.
+///
+/// # Safety
+///
+/// `ptr` must point to a flattened device tree, readable up to its
+/// `totalsize`.
+pub unsafe fn init(ptr: *const u8) -> Result<&'static DeviceTree, Error> {
+    let header = slice::from_raw_parts(ptr, HEADER_SIZE);
+    let total_size = be32(header, 4)? as usize;
+    let tree = DeviceTree::parse(slice::from_raw_parts(ptr, total_size))?;
+    Ok(DEVICE_TREE.call_once(|| tree))
+}
+
+/// The device tree parsed by [`init`].
+pub fn tree() -> &'static DeviceTree {
+    DEVICE_TREE.get().expect("device tree not parsed yet")
+}
+
+fn be32(blob: &[u8], offset: usize) -> Result<u32, Error> {
+    blob.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(Error::Truncated(offset))
+}
+
+fn be64(blob: &[u8], offset: usize) -> Result<u64, Error> {
+    Ok((be32(blob, offset)? as u64) << 32 | be32(blob, offset + 4)? as u64)
+}
+
+/// The NUL-terminated string at `offset`.
+fn c_str(blob: &[u8], offset: usize) -> Result<&str, Error> {
+    let bytes = blob.get(offset..).ok_or(Error::Truncated(offset))?;
+    let len = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(Error::Truncated(offset))?;
+    str::from_utf8(&bytes[..len]).map_err(|_| Error::BadName(offset))
+}
+
+impl DeviceTree {
+    pub fn parse(blob: &[u8]) -> Result<DeviceTree, Error> {
+        let magic = be32(blob, 0)?;
+        if magic != FDT_MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
+        let total_size = be32(blob, 4)? as usize;
+        let off_struct = be32(blob, 8)? as usize;
+        let off_strings = be32(blob, 12)? as usize;
+        let off_reserved = be32(blob, 16)? as usize;
+        let last_comp_version = be32(blob, 24)?;
+        if last_comp_version > LAST_COMP_VERSION {
+            return Err(Error::UnsupportedVersion(last_comp_version));
+        }
+        if blob.len() < total_size {
+            return Err(Error::Truncated(blob.len()));
+        }
+
+        let mut reserved = Vec::new();
+        let mut offset = off_reserved;
+        loop {
+            let (start, size) = (be64(blob, offset)?, be64(blob, offset + 8)?);
+            if size == 0 {
+                break;
+            }
+            reserved.push((start as usize, (start + size) as usize));
+            offset += 16;
+        }
+
+        let mut tree = DeviceTree {
+            nodes: Vec::new(),
+            phandles: BTreeMap::new(),
+            reserved,
+            total_size,
+        };
+        let strings = blob
+            .get(off_strings..)
+            .ok_or(Error::Truncated(off_strings))?;
+        // nodes whose FDT_END_NODE is still to come
+        let mut open: Vec<NodeId> = Vec::new();
+        let mut offset = off_struct;
+        loop {
+            let token = be32(blob, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(blob, offset)?;
+                    offset = (offset + name.len() + 1).next_multiple_of(4);
+                    let id = tree.nodes.len();
+                    let parent = open.last().copied();
+                    if let Some(parent) = parent {
+                        tree.nodes[parent].children.push(id);
+                    }
+                    tree.nodes.push(NodeData {
+                        name: name.to_string(),
+                        parent,
+                        children: Vec::new(),
+                        properties: Vec::new(),
+                    });
+                    open.push(id);
+                }
+                FDT_END_NODE => {
+                    open.pop().ok_or(Error::BadToken {
+                        offset: offset - 4,
+                        token,
+                    })?;
+                }
+                FDT_PROP => {
+                    let len = be32(blob, offset)? as usize;
+                    let name_offset = be32(blob, offset + 4)? as usize;
+                    let value = blob
+                        .get(offset + 8..offset + 8 + len)
+                        .ok_or(Error::Truncated(offset + 8))?;
+                    let &id = open.last().ok_or(Error::BadToken {
+                        offset: offset - 4,
+                        token,
+                    })?;
+                    let name = c_str(strings, name_offset)?;
+                    if (name == "phandle" || name == "linux,phandle") && len == 4 {
+                        tree.phandles.insert(be32(value, 0)?, id);
+                    }
+                    tree.nodes[id].properties.push(Property {
+                        name: name.to_string(),
+                        value: value.to_vec(),
+                    });
+                    offset = (offset + 8 + len).next_multiple_of(4);
+                }
+                FDT_NOP => {}
+                FDT_END => break,
+                _ => {
+                    return Err(Error::BadToken {
+                        offset: offset - 4,
+                        token,
+                    })
+                }
+            }
+        }
+        if tree.nodes.is_empty() {
+            return Err(Error::Truncated(off_struct));
+        }
+        Ok(tree)
+    }
+
+    /// Size of the blob the tree was parsed from.
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    /// `/memreserve/` entries of the blob, as `[start, end)`.
+    pub fn memreserve(&self) -> &[(usize, usize)] {
+        &self.reserved
+    }
+
+    pub fn root(&self) -> Node<'_> {
+        self.node(0)
+    }
+
+    fn node(&self, id: NodeId) -> Node<'_> {
+        Node { tree: self, id }
+    }
+
+    /// Every node, parents before their children.
+    pub fn nodes(&self) -> impl Iterator<Item = Node<'_>> {
+        (0..self.nodes.len()).map(|id| self.node(id))
+    }
+
+    /// The node at an absolute `path`. A component without a unit address
+    /// matches a node whose name only differs by one.
+    pub fn find_node(&self, path: &str) -> Option<Node<'_>> {
+        path.split('/')
+            .filter(|component| !component.is_empty())
+            .try_fold(self.root(), |node, component| {
+                node.children().find(|child| {
+                    child.name() == component
+                        || (!component.contains('@') && child.unit_name() == component)
+                })
+            })
+    }
+
+    pub fn find_by_phandle(&self, phandle: u32) -> Option<Node<'_>> {
+        self.phandles.get(&phandle).map(|&id| self.node(id))
+    }
+
+    /// Enabled nodes listing `compatible`.
+    pub fn find_compatible<'a>(&'a self, compatible: &'a str) -> impl Iterator<Item = Node<'a>> {
+        self.nodes()
+            .filter(move |node| node.is_compatible(compatible) && node.is_enabled())
+    }
+
+    /// Enabled nodes whose `device_type` is `device_type`.
+    pub fn find_device_type<'a>(&'a self, device_type: &'a str) -> impl Iterator<Item = Node<'a>> {
+        self.nodes().filter(move |node| {
+            node.property("device_type").and_then(Property::as_str) == Some(device_type)
+                && node.is_enabled()
+        })
+    }
+}
+
+impl Property {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Big-endian 32-bit cells of the value.
+    pub fn cells(&self) -> impl Iterator<Item = u32> + '_ {
+        self.value
+            .chunks_exact(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        (self.value.len() == 4).then(|| self.cells().next().unwrap())
+    }
+
+    /// A value of one or two cells.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 | 8 => Some(read_cells(&mut self.cells(), self.value.len() / 4)),
+            _ => None,
+        }
+    }
+
+    /// A NUL-terminated string.
+    pub fn as_str(&self) -> Option<&str> {
+        let (last, bytes) = self.value.split_last()?;
+        if *last != 0 {
+            return None;
+        }
+        str::from_utf8(bytes).ok()
+    }
+
+    /// A list of NUL-terminated strings.
+    pub fn as_str_list(&self) -> impl Iterator<Item = &str> {
+        self.value
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| str::from_utf8(s).ok())
+    }
+}
+
+/// Assemble a number of `count` cells, the most significant first.
+fn read_cells(cells: &mut impl Iterator<Item = u32>, count: usize) -> u64 {
+    cells
+        .take(count)
+        .fold(0, |value, cell| value << 32 | cell as u64)
+}
+
+impl<'a> Node<'a> {
+    fn data(&self) -> &'a NodeData {
+        &self.tree.nodes[self.id]
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Full name, unit address included.
+    pub fn name(&self) -> &'a str {
+        &self.data().name
+    }
+
+    /// Name without the unit address.
+    pub fn unit_name(&self) -> &'a str {
+        let name = self.name();
+        name.split_once('@').map_or(name, |(name, _)| name)
+    }
+
+    pub fn parent(&self) -> Option<Node<'a>> {
+        self.data().parent.map(|id| self.tree.node(id))
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = Node<'a>> + 'a {
+        let tree = self.tree;
+        self.data().children.iter().map(move |&id| tree.node(id))
+    }
+
+    pub fn properties(&self) -> impl Iterator<Item = &'a Property> {
+        self.data().properties.iter()
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'a Property> {
+        self.properties().find(|property| property.name == name)
+    }
+
+    pub fn compatible(&self) -> impl Iterator<Item = &'a str> {
+        self.property("compatible")
+            .into_iter()
+            .flat_map(Property::as_str_list)
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|c| c == compatible)
+    }
+
+    /// Whether `status` is missing, `okay` or `ok`.
+    pub fn is_enabled(&self) -> bool {
+        self.property("status")
+            .and_then(Property::as_str)
+            .is_none_or(|status| status == "okay" || status == "ok")
+    }
+
+    /// `#address-cells` of the children of this node.
+    pub fn address_cells(&self) -> usize {
+        self.property("#address-cells")
+            .and_then(Property::as_u32)
+            .map_or(2, |cells| cells as usize)
+    }
+
+    /// `#size-cells` of the children of this node.
+    pub fn size_cells(&self) -> usize {
+        self.property("#size-cells")
+            .and_then(Property::as_u32)
+            .map_or(1, |cells| cells as usize)
+    }
+
+    /// `reg`, as `[start, end)` ranges in the address space of the parent.
+    pub fn reg(&self) -> Vec<(usize, usize)> {
+        let Some(reg) = self.property("reg") else {
+            return Vec::new();
+        };
+        let (address_cells, size_cells) = self.parent().map_or((2, 1), |parent| {
+            (parent.address_cells(), parent.size_cells())
+        });
+        let entry = address_cells + size_cells;
+        if entry == 0 {
+            return Vec::new();
+        }
+        let mut cells = reg.cells();
+        (0..reg.value.len() / 4 / entry)
+            .map(|_| {
+                let start = read_cells(&mut cells, address_cells) as usize;
+                let size = read_cells(&mut cells, size_cells) as usize;
+                (start, start + size)
+            })
+            .collect()
+    }
+
+    /// The interrupt controller this node's `interrupts` go to: the one named
+    /// by the nearest `interrupt-parent` up the tree.
+    pub fn interrupt_parent(&self) -> Option<Node<'a>> {
+        let mut node = Some(*self);
+        while let Some(current) = node {
+            if let Some(phandle) = current
+                .property("interrupt-parent")
+                .and_then(Property::as_u32)
+            {
+                return self.tree.find_by_phandle(phandle);
+            }
+            node = current.parent();
+        }
+        None
+    }
+
+    /// `#interrupt-cells` of this interrupt controller.
+    pub fn interrupt_cells(&self) -> usize {
+        self.property("#interrupt-cells")
+            .and_then(Property::as_u32)
+            .map_or(1, |cells| cells as usize)
+    }
+
+    /// `interrupts`, one specifier per entry, each of `#interrupt-cells` of
+    /// the interrupt parent. Most controllers take the source number first.
+    pub fn interrupts(&self) -> Vec<Vec<u32>> {
+        let Some(interrupts) = self.property("interrupts") else {
+            return Vec::new();
+        };
+        let cells = self
+            .interrupt_parent()
+            .map_or(1, |parent| parent.interrupt_cells())
+            .max(1);
+        interrupts
+            .cells()
+            .collect::<Vec<_>>()
+            .chunks_exact(cells)
+            .map(<[u32]>::to_vec)
+            .collect()
+    }
+
+    /// `interrupts-extended`, as the controller and specifier of every entry.
+    pub fn interrupts_extended(&self) -> Vec<(Node<'a>, Vec<u32>)> {
+        let Some(property) = self.property("interrupts-extended") else {
+            return Vec::new();
+        };
+        let mut cells = property.cells();
+        let mut entries = Vec::new();
+        while let Some(phandle) = cells.next() {
+            let Some(controller) = self.tree.find_by_phandle(phandle) else {
+                break;
+            };
+            let specifier: Vec<u32> = cells.by_ref().take(controller.interrupt_cells()).collect();
+            entries.push((controller, specifier));
+        }
+        entries
+    }
+
+    /// Absolute path of the node.
+    pub fn path(&self) -> String {
+        let mut components = Vec::new();
+        let mut node = Some(*self);
+        while let Some(current) = node {
+            if current.parent().is_some() {
+                components.push(current.name());
+            }
+            node = current.parent();
+        }
+        if components.is_empty() {
+            return "/".to_string();
+        }
+        components
+            .iter()
+            .rev()
+            .fold(String::new(), |path, name| path + "/" + name)
+    }
+}
+
+impl fmt::Debug for Node<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path())
+    }
+}
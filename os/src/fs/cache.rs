@@ -0,0 +1,303 @@
+//! Block buffer cache
+//!
+//! A [`BlockCache`] keeps recently used blocks of a [`StorageDevice`] in
+//! memory and hands them out as shared [`Buffer`]s. Buffers written to are
+//! marked dirty and reach the device on [`BlockCache::sync`], when they are
+//! evicted, or periodically from an idle hart through [`writeback_if_due`].
+//! When the cache is full, the least recently used buffer nobody else holds is
+//! evicted.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use log::*;
+use spin::{Mutex, Once, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::time::{self, Duration};
+
+use super::{StorageDevice, StorageError};
+
+/// How often dirty buffers are written back.
+const WRITEBACK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Caches made with [`BlockCache::new`], for [`caches`] and [`sync_all`].
+static CACHES: Mutex<Vec<Weak<BlockCache>>> = Mutex::new(Vec::new());
+/// Started along with the first cache.
+static WRITEBACK_TIMER: Once = Once::new();
+/// Set by the write-back timer, cleared by [`writeback_if_due`].
+static WRITEBACK_DUE: AtomicBool = AtomicBool::new(false);
+
+pub struct BlockCache {
+    device: Arc<dyn StorageDevice + Send + Sync>,
+    block_size: usize,
+    /// most buffers kept
+    capacity: usize,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    writebacks: AtomicU64,
+    evictions: AtomicU64,
+}
+
+struct Inner {
+    /// block number to `(buffer, last use)`
+    buffers: BTreeMap<u64, (Arc<Buffer>, u64)>,
+    /// last use to block number, least recent first
+    lru: BTreeMap<u64, u64>,
+    /// counts uses, to order them
+    clock: u64,
+}
+
+/// A cached block. The data can be shared by several readers or taken by one
+/// writer, which marks the buffer dirty.
+pub struct Buffer {
+    block: u64,
+    data: RwLock<Vec<u8>>,
+    dirty: AtomicBool,
+}
+
+impl Buffer {
+    pub fn read(&self) -> RwLockReadGuard<'_, Vec<u8>> {
+        self.data.read()
+    }
+
+    /// Take the data for writing. The buffer is dirty from now on, until
+    /// written back.
+    pub fn write(&self) -> RwLockWriteGuard<'_, Vec<u8>> {
+        let data = self.data.write();
+        self.dirty.store(true, Ordering::Relaxed);
+        data
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    /// Write the data to `device` if dirty. Writers are held off meanwhile,
+    /// so nothing written in between is lost.
+    fn write_back(&self, data: &[u8], device: &dyn StorageDevice) -> Result<bool, StorageError> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(false);
+        }
+        device.write_blocks(self.block, data).inspect_err(|_| {
+            self.dirty.store(true, Ordering::Relaxed);
+        })?;
+        Ok(true)
+    }
+}
+
+/// Counters of a [`BlockCache`].
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub writebacks: u64,
+    pub evictions: u64,
+    /// buffers held now
+    pub cached: usize,
+    pub dirty: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hit_rate = (self.hits * 100)
+            .checked_div(self.hits + self.misses)
+            .unwrap_or(0);
+        write!(
+            f,
+            "{} hits, {} misses ({}% hit), {} written back, {} evicted, {} cached ({} dirty)",
+            self.hits,
+            self.misses,
+            hit_rate,
+            self.writebacks,
+            self.evictions,
+            self.cached,
+            self.dirty
+        )
+    }
+}
+
+impl BlockCache {
+    /// Cache up to `capacity` blocks of `device`, writing dirty ones back
+    /// every [`WRITEBACK_INTERVAL`].
+    pub fn new(device: Arc<dyn StorageDevice + Send + Sync>, capacity: usize) -> Arc<BlockCache> {
+        assert!(capacity > 0);
+        let cache = Arc::new(BlockCache {
+            block_size: device.block_size(),
+            device,
+            capacity,
+            inner: Mutex::new(Inner {
+                buffers: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            writebacks: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        });
+        let mut caches = CACHES.lock();
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.push(Arc::downgrade(&cache));
+        drop(caches);
+        WRITEBACK_TIMER.call_once(schedule_writeback);
+        cache
+    }
+
+    pub fn device(&self) -> &Arc<dyn StorageDevice + Send + Sync> {
+        &self.device
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// The buffer of `block`, read from the device unless cached.
+    pub fn get(&self, block: u64) -> Result<Arc<Buffer>, StorageError> {
+        let mut inner = self.inner.lock();
+        inner.clock += 1;
+        let now = inner.clock;
+        if let Some((buffer, last_use)) = inner.buffers.get_mut(&block) {
+            let buffer = buffer.clone();
+            let last_use = core::mem::replace(last_use, now);
+            inner.lru.remove(&last_use);
+            inner.lru.insert(now, block);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(buffer);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if inner.buffers.len() >= self.capacity {
+            self.evict(&mut inner)?;
+        }
+        // the lock is held while reading, so that nobody else reads the
+        // block into a buffer of their own
+        let mut data = vec![0; self.block_size];
+        self.device.read_blocks(block, &mut data)?;
+        let buffer = Arc::new(Buffer {
+            block,
+            data: RwLock::new(data),
+            dirty: AtomicBool::new(false),
+        });
+        inner.buffers.insert(block, (buffer.clone(), now));
+        inner.lru.insert(now, block);
+        Ok(buffer)
+    }
+
+    /// Copy `len` bytes at byte `offset` of the device into `buf`, through the
+    /// cache.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), StorageError> {
+        let block_size = self.block_size as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % block_size) as usize;
+            let len = (self.block_size - start).min(buf.len() - done);
+            let buffer = self.get(pos / block_size)?;
+            buf[done..done + len].copy_from_slice(&buffer.read()[start..start + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Drop the least recently used buffer nobody holds, writing it back
+    /// first if dirty. The cache grows past its capacity when every buffer is
+    /// held.
+    fn evict(&self, inner: &mut Inner) -> Result<(), StorageError> {
+        let victim = inner.lru.iter().find_map(|(&last_use, &block)| {
+            let (buffer, _) = &inner.buffers[&block];
+            (Arc::strong_count(buffer) == 1).then_some((last_use, block))
+        });
+        let Some((last_use, block)) = victim else {
+            debug!("[kernel] block cache: every buffer is in use");
+            return Ok(());
+        };
+        let (buffer, _) = &inner.buffers[&block];
+        if buffer.write_back(&buffer.read(), &*self.device)? {
+            self.writebacks.fetch_add(1, Ordering::Relaxed);
+        }
+        inner.lru.remove(&last_use);
+        inner.buffers.remove(&block);
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Write every dirty buffer back and flush the device.
+    pub fn sync(&self) -> Result<(), StorageError> {
+        let buffers: Vec<_> = {
+            let inner = self.inner.lock();
+            inner
+                .buffers
+                .values()
+                .filter(|(buffer, _)| buffer.is_dirty())
+                .map(|(buffer, _)| buffer.clone())
+                .collect()
+        };
+        for buffer in buffers {
+            if buffer.write_back(&buffer.read(), &*self.device)? {
+                self.writebacks.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.device.flush()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let (cached, dirty) = {
+            let inner = self.inner.lock();
+            let dirty = inner
+                .buffers
+                .values()
+                .filter(|(buffer, _)| buffer.is_dirty())
+                .count();
+            (inner.buffers.len(), dirty)
+        };
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            writebacks: self.writebacks.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            cached,
+            dirty,
+        }
+    }
+}
+
+/// Mark the caches due for write-back every [`WRITEBACK_INTERVAL`]. The timer
+/// runs in an interrupt handler, which must not wait for the device, so the
+/// writing is left to [`writeback_if_due`].
+fn schedule_writeback() {
+    time::add_timer_after(WRITEBACK_INTERVAL, || {
+        WRITEBACK_DUE.store(true, Ordering::Release);
+        schedule_writeback();
+    });
+}
+
+/// Write back every block cache if the timer says so. Idle harts call this,
+/// outside of interrupt handlers.
+pub fn writeback_if_due() {
+    if !WRITEBACK_DUE.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    for cache in caches() {
+        if let Err(e) = cache.sync() {
+            warn!("[kernel] block cache: write back: {}", e);
+        }
+    }
+}
+
+/// Every block cache still in use.
+pub fn caches() -> Vec<Arc<BlockCache>> {
+    CACHES.lock().iter().filter_map(Weak::upgrade).collect()
+}
+
+/// Write back every block cache.
+pub fn sync_all() -> Result<(), StorageError> {
+    caches().iter().try_for_each(|cache| cache.sync())
+}
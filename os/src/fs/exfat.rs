@@ -1,3 +1,22 @@
+//! Read-only exFAT
+//!
+//! The volume is expected at the start of the device. Mounting checks the
+//! main boot region, or the backup one if the main one is damaged, then reads
+//! the allocation bitmap and the up-case table from the root directory. Names
+//! are compared case-insensitively through the up-case table, as exFAT
+//! requires.
+//!
+//! The FAT and the directories are read through a [`BlockCache`], so those in
+//! use stay in memory. File data is read straight from the device instead, a
+//! run of contiguous clusters at a time, and never held whole in memory.
+
+use alloc::{boxed::Box, string::String, sync::Arc, vec, vec::Vec};
+
+use log::*;
+use spin::Mutex;
+
+use super::{cache::BlockCache, DirEntry, FileSystem, FsError, StorageDevice};
+
 #[repr(C, packed)]
 pub struct ExFatBootSector {
     pub jump_boot: [u8; 3],            // Jump instruction to boot code
@@ -23,59 +42,758 @@ pub struct ExFatBootSector {
     pub boot_signature: u16,           // Boot sector signature (0xAA55)
 }
 
+impl ExFatBootSector {
+    pub fn from_bytes(bytes: &[u8; 512]) -> ExFatBootSector {
+        unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const ExFatBootSector) }
+    }
+
+    /// Whether this looks like an exFAT boot sector whose fields are in the
+    /// ranges the specification allows.
+    pub fn is_valid(&self) -> bool {
+        let bytes_per_sector_shift = self.bytes_per_sector_shift;
+        let sectors_per_cluster_shift = self.sectors_per_cluster_shift;
+        let cluster_count = self.cluster_count;
+        let root_dir_cluster = self.root_dir_cluster;
+        let fs_revision = self.fs_revision;
+        let boot_signature = self.boot_signature;
+        &self.fs_name == b"EXFAT   "
+            && self.must_be_zero.iter().all(|&b| b == 0)
+            && boot_signature == 0xAA55
+            && (9..=12).contains(&bytes_per_sector_shift)
+            && sectors_per_cluster_shift <= 25 - bytes_per_sector_shift
+            && (1..=2).contains(&self.number_of_fats)
+            && fs_revision >> 8 == 1
+            && (FIRST_CLUSTER..cluster_count + FIRST_CLUSTER).contains(&root_dir_cluster)
+    }
+}
+
+/// Directory entry of a file or directory, the first of its entry set, which
+/// goes on with a stream extension and file name entries.
 #[repr(C, packed)]
 pub struct ExFatFileEntry {
-    entry_type: u8,      // Entry type (e.g., file, directory)
+    entry_type: u8,      // Entry type (0x85)
     secondary_count: u8, // Number of secondary entries
-    name_length: u8,
-    name_hash: u16,
+    set_checksum: u16,   // Checksum of the entry set
+    file_attributes: u16,
+    reserved1: u16,
+    create_timestamp: u32,
+    last_modified_timestamp: u32,
+    last_accessed_timestamp: u32,
+    create_10ms_increment: u8,
+    last_modified_10ms_increment: u8,
+    create_utc_offset: u8,
+    last_modified_utc_offset: u8,
+    last_accessed_utc_offset: u8,
+    reserved2: [u8; 7],
+}
+
+/// Stream extension entry, where the data of a file or directory is.
+#[repr(C, packed)]
+pub struct ExFatStreamEntry {
+    entry_type: u8, // Entry type (0xC0)
+    flags: u8,      // AllocationPossible and NoFatChain
+    reserved1: u8,
+    name_length: u8, // Name length in UTF-16 code units
+    name_hash: u16,  // Hash of the up-cased name
+    reserved2: u16,
+    valid_data_length: u64, // Bytes written, the rest reads as zeros
+    reserved3: u32,
     first_cluster: u32, // Start cluster of file data
     data_length: u64,   // File size
 }
 
 pub type Cluster = u32;
 pub const EXFAT_EOF: Cluster = 0xFFFFFFFF; // End of File marker
+const EXFAT_BAD: Cluster = 0xFFFFFFF7; // Bad cluster marker
+/// Clusters are numbered from 2.
+const FIRST_CLUSTER: Cluster = 2;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_BITMAP: u8 = 0x81;
+const ENTRY_UPCASE: u8 = 0x82;
+const ENTRY_LABEL: u8 = 0x83;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xC0;
+const ENTRY_NAME: u8 = 0xC1;
+
+const ATTR_DIRECTORY: u16 = 0x10;
+const STREAM_NO_FAT_CHAIN: u8 = 0x02;
+/// Volume flag: the second FAT and bitmap are the active ones.
+const VOLUME_ACTIVE_FAT: u16 = 0x01;
+/// UTF-16 code units in a file name entry.
+const NAME_CHARS_PER_ENTRY: usize = 15;
+/// Bytes of an up-case table which maps every UTF-16 code unit.
+const MAX_UPCASE_SIZE: u64 = 0x10000 * 2;
+
+/// Up-case table, as the characters which do not map to themselves.
+struct UpcaseTable(Vec<(u16, u16)>);
+
+impl UpcaseTable {
+    /// Expand the table as stored, where `0xFFFF, n` stands for `n`
+    /// characters mapping to themselves.
+    fn parse(data: &[u8]) -> UpcaseTable {
+        let mut mappings = Vec::new();
+        let mut units = data
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+        let mut ch: u32 = 0;
+        while let Some(upper) = units.next() {
+            if ch > u16::MAX as u32 {
+                break;
+            }
+            if upper == 0xFFFF {
+                ch += units.next().unwrap_or(0) as u32;
+                continue;
+            }
+            if upper as u32 != ch {
+                mappings.push((ch as u16, upper));
+            }
+            ch += 1;
+        }
+        UpcaseTable(mappings)
+    }
+
+    fn upcase(&self, ch: u16) -> u16 {
+        match self.0.binary_search_by_key(&ch, |&(ch, _)| ch) {
+            Ok(index) => self.0[index].1,
+            Err(_) => ch,
+        }
+    }
+
+    fn eq(&self, a: &[u16], b: &[u16]) -> bool {
+        a.len() == b.len()
+            && a.iter()
+                .zip(b)
+                .all(|(&a, &b)| self.upcase(a) == self.upcase(b))
+    }
+
+    /// Hash of a name as kept in its stream extension entry.
+    fn name_hash(&self, name: &[u16]) -> u16 {
+        name.iter()
+            .flat_map(|&ch| self.upcase(ch).to_le_bytes())
+            .fold(0u16, |hash, byte| {
+                hash.rotate_right(1).wrapping_add(byte as u16)
+            })
+    }
+}
+
+/// A file or directory, from its directory entry set.
+#[derive(Debug, Clone)]
+struct Node {
+    name: Vec<u16>,
+    is_dir: bool,
+    data: Extent,
+}
 
-pub struct ExFatFAT<'a> {
-    pub fat_data: &'a [u8], // Raw FAT data
+/// Where the data of a file or directory lives.
+#[derive(Debug, Clone, Copy)]
+struct Extent {
+    first_cluster: Cluster,
+    /// bytes allocated, `None` for the root directory which only has a chain
+    data_length: Option<u64>,
+    /// bytes written, the rest reads as zeros
+    valid_data_length: u64,
+    /// the clusters are contiguous and not in the FAT
+    no_fat_chain: bool,
 }
 
-impl<'a> ExFatFAT<'a> {
-    pub fn next_cluster(&self, cluster: Cluster) -> Option<Cluster> {
-        let entry_offset = cluster as usize * 4;
-        let next_cluster = u32::from_le_bytes(
-            self.fat_data[entry_offset..entry_offset + 4]
-                .try_into()
-                .unwrap(),
+/// How far a FAT chain has been followed, so that reading on from there does
+/// not start over at its first cluster.
+#[derive(Debug, Clone, Copy)]
+struct ChainCursor {
+    first_cluster: Cluster,
+    index: u64,
+    cluster: Cluster,
+}
+
+pub struct ExFat {
+    cache: Arc<BlockCache>,
+    bytes_per_sector_shift: u8,
+    sectors_per_cluster_shift: u8,
+    /// sector of the active FAT
+    fat_offset: u64,
+    cluster_heap_offset: u64,
+    cluster_count: u32,
+    root_dir_cluster: Cluster,
+    upcase: UpcaseTable,
+    /// the allocation bitmap, one bit per cluster of the heap set when the
+    /// cluster is in use, from cluster 2
+    bitmap: Extent,
+    label: String,
+    cursor: Mutex<Option<ChainCursor>>,
+}
+
+impl ExFat {
+    /// Mount the exFAT volume on the device `cache` is over.
+    pub fn mount(cache: Arc<BlockCache>) -> Result<ExFat, FsError> {
+        let boot = match read_boot_region(&cache, 0) {
+            Err(FsError::Corrupted) => {
+                warn!("[kernel] exFAT: main boot region is damaged, trying the backup");
+                // the backup follows the 12 sectors of the main region, of a
+                // size the damaged boot sector cannot be trusted with
+                (9..=12)
+                    .find_map(|shift| {
+                        read_boot_region(&cache, 12 << shift)
+                            .ok()
+                            .filter(|boot| boot.bytes_per_sector_shift == shift)
+                    })
+                    .ok_or(FsError::Corrupted)?
+            }
+            result => result?,
+        };
+        let volume_length = boot.volume_length;
+        let device = cache.device();
+        let device_bytes = device
+            .num_blocks()
+            .saturating_mul(device.block_size() as u64);
+        if volume_length
+            .checked_mul(1 << boot.bytes_per_sector_shift)
+            .is_none_or(|bytes| bytes > device_bytes)
+        {
+            warn!(
+                "[kernel] exFAT: volume of {} sectors does not fit on the device",
+                volume_length
+            );
+            return Err(FsError::Corrupted);
+        }
+        let volume_flags = boot.volume_flags;
+        let active_fat = (volume_flags & VOLUME_ACTIVE_FAT != 0 && boot.number_of_fats == 2) as u8;
+        let mut fs = ExFat {
+            cache,
+            bytes_per_sector_shift: boot.bytes_per_sector_shift,
+            sectors_per_cluster_shift: boot.sectors_per_cluster_shift,
+            fat_offset: boot.fat_offset as u64 + active_fat as u64 * boot.fat_length as u64,
+            cluster_heap_offset: boot.cluster_heap_offset as u64,
+            cluster_count: boot.cluster_count,
+            root_dir_cluster: boot.root_dir_cluster,
+            upcase: UpcaseTable(Vec::new()),
+            bitmap: Extent {
+                first_cluster: 0,
+                data_length: Some(0),
+                valid_data_length: 0,
+                no_fat_chain: false,
+            },
+            label: String::new(),
+            cursor: Mutex::new(None),
+        };
+
+        // the critical primary entries of the root directory
+        let mut upcase = None;
+        let mut bitmap = None;
+        let mut label = String::new();
+        fs.for_each_entry(&fs.root_extent(), |entry| {
+            match entry[0] {
+                ENTRY_END => return false,
+                ENTRY_BITMAP if entry[1] & 1 == active_fat => bitmap = Some(*entry),
+                ENTRY_UPCASE => upcase = Some(*entry),
+                ENTRY_LABEL => {
+                    let len = (entry[1] as usize).min(11);
+                    label = decode_name(&utf16(&entry[2..2 + len * 2]));
+                }
+                _ => {}
+            }
+            true
+        })?;
+        let (Some(upcase), Some(bitmap)) = (upcase, bitmap) else {
+            warn!("[kernel] exFAT: no up-case table or allocation bitmap");
+            return Err(FsError::Corrupted);
+        };
+
+        let extent = fs.entry_extent(&bitmap)?;
+        if extent.data_length.unwrap_or(0) * 8 < fs.cluster_count as u64 {
+            return Err(FsError::Corrupted);
+        }
+        fs.bitmap = extent;
+
+        let extent = fs.entry_extent(&upcase)?;
+        let length = extent.data_length.unwrap_or(0);
+        if length > MAX_UPCASE_SIZE {
+            warn!("[kernel] exFAT: up-case table of {} bytes", length);
+            return Err(FsError::Corrupted);
+        }
+        let mut data = vec![0; length as usize];
+        fs.read_extent(&extent, 0, &mut data)?;
+        let checksum = u32::from_le_bytes(upcase[4..8].try_into().unwrap());
+        if table_checksum(&data) != checksum {
+            warn!("[kernel] exFAT: up-case table checksum mismatch");
+            return Err(FsError::Corrupted);
+        }
+        fs.upcase = UpcaseTable::parse(&data);
+        fs.label = label;
+        info!(
+            "[kernel] exFAT volume {:?}: {} clusters of {} bytes, {} in use",
+            fs.label,
+            fs.cluster_count,
+            fs.cluster_size(),
+            fs.count_allocated()?
         );
-        if next_cluster == EXFAT_EOF {
-            None
+        Ok(fs)
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << (self.bytes_per_sector_shift + self.sectors_per_cluster_shift)
+    }
+
+    fn is_valid_cluster(&self, cluster: Cluster) -> bool {
+        (FIRST_CLUSTER..self.cluster_count + FIRST_CLUSTER).contains(&cluster)
+    }
+
+    /// Whether `extent` fits in the cluster heap, as far as can be told
+    /// without following its chain.
+    fn is_valid_extent(&self, extent: &Extent) -> bool {
+        let Some(data_length) = extent.data_length.filter(|&len| len != 0) else {
+            return true;
+        };
+        let clusters = data_length.div_ceil(self.cluster_size());
+        let available = if extent.no_fat_chain {
+            (self.cluster_count + FIRST_CLUSTER).saturating_sub(extent.first_cluster)
         } else {
-            Some(next_cluster)
+            self.cluster_count
+        };
+        self.is_valid_cluster(extent.first_cluster) && clusters <= available as u64
+    }
+
+    /// Byte offset of `cluster` on the device.
+    fn cluster_offset(&self, cluster: Cluster) -> u64 {
+        let sector = self.cluster_heap_offset
+            + (((cluster - FIRST_CLUSTER) as u64) << self.sectors_per_cluster_shift);
+        sector << self.bytes_per_sector_shift
+    }
+
+    /// The cluster after `cluster` in the FAT, `None` at the end of the chain.
+    fn next_cluster(&self, cluster: Cluster) -> Result<Option<Cluster>, FsError> {
+        let offset = (self.fat_offset << self.bytes_per_sector_shift) + cluster as u64 * 4;
+        let mut entry = [0; 4];
+        self.cache.read_at(offset, &mut entry)?;
+        match u32::from_le_bytes(entry) {
+            EXFAT_EOF => Ok(None),
+            next if self.is_valid_cluster(next) => Ok(Some(next)),
+            EXFAT_BAD => {
+                warn!("[kernel] exFAT: bad cluster after {}", cluster);
+                Err(FsError::Corrupted)
+            }
+            _ => Err(FsError::Corrupted),
         }
     }
-}
 
-pub struct AllocationBitmap<'a> {
-    pub bitmap_data: &'a mut [u8], // Raw bitmap data
+    /// Cluster `index` of `extent`, which must be within its data length.
+    fn cluster_at(&self, extent: &Extent, index: u64) -> Result<Cluster, FsError> {
+        if extent.no_fat_chain {
+            return Ok(extent.first_cluster + index as Cluster);
+        }
+        // the lock is not held while the FAT is read
+        let (mut at, mut cluster) = match *self.cursor.lock() {
+            Some(cursor)
+                if cursor.first_cluster == extent.first_cluster && cursor.index <= index =>
+            {
+                (cursor.index, cursor.cluster)
+            }
+            _ => (0, extent.first_cluster),
+        };
+        while at < index {
+            let Some(next) = self.next_cluster(cluster)? else {
+                warn!(
+                    "[kernel] exFAT: chain from cluster {} is shorter than its data",
+                    extent.first_cluster
+                );
+                return Err(FsError::Corrupted);
+            };
+            cluster = next;
+            at += 1;
+        }
+        *self.cursor.lock() = Some(ChainCursor {
+            first_cluster: extent.first_cluster,
+            index,
+            cluster,
+        });
+        Ok(cluster)
+    }
+
+    /// The clusters of `extent` from `index` that follow each other on the
+    /// device, at most `max` of them, as the first one and how many.
+    fn run_at(&self, extent: &Extent, index: u64, max: u64) -> Result<(Cluster, u64), FsError> {
+        let first = self.cluster_at(extent, index)?;
+        if extent.no_fat_chain {
+            return Ok((first, max));
+        }
+        let mut count = 1;
+        while count < max && self.cluster_at(extent, index + count)? == first + count as Cluster {
+            count += 1;
+        }
+        Ok((first, count))
+    }
+
+    fn root_extent(&self) -> Extent {
+        Extent {
+            first_cluster: self.root_dir_cluster,
+            data_length: None,
+            valid_data_length: 0,
+            no_fat_chain: false,
+        }
+    }
+
+    /// Extent of a bitmap or up-case table entry, which are always FAT
+    /// chains.
+    fn entry_extent(&self, entry: &[u8]) -> Result<Extent, FsError> {
+        let first_cluster = u32::from_le_bytes(entry[20..24].try_into().unwrap());
+        let data_length = u64::from_le_bytes(entry[24..32].try_into().unwrap());
+        let extent = Extent {
+            first_cluster,
+            data_length: Some(data_length),
+            valid_data_length: data_length,
+            no_fat_chain: false,
+        };
+        if !self.is_valid_cluster(first_cluster) || !self.is_valid_extent(&extent) {
+            return Err(FsError::Corrupted);
+        }
+        Ok(extent)
+    }
+
+    /// Read the data of `extent` from byte `offset` into `buf`, returning how
+    /// many bytes were read, fewer than asked at the end of the data.
+    ///
+    /// File data goes around the cache: each run of clusters that follow
+    /// each other on the device is read with a single request, and only the
+    /// blocks it partly covers are read through the cache.
+    fn read_extent(&self, extent: &Extent, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data_length = extent.data_length.unwrap_or(0);
+        let len = data_length.saturating_sub(offset).min(buf.len() as u64) as usize;
+        let valid = extent.valid_data_length.min(data_length);
+        let cluster_size = self.cluster_size();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            if pos >= valid {
+                buf[done..len].fill(0);
+                break;
+            }
+            let want = ((len - done) as u64).min(valid - pos);
+            let within = pos % cluster_size;
+            let clusters = (within + want).div_ceil(cluster_size);
+            let (cluster, count) = self.run_at(extent, pos / cluster_size, clusters)?;
+            let n = (count * cluster_size - within).min(want) as usize;
+            self.read_direct(
+                self.cluster_offset(cluster) + within,
+                &mut buf[done..done + n],
+            )?;
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// Read `buf` from byte `offset` of the device: whole blocks straight
+    /// from the device, the blocks at either end through the cache.
+    fn read_direct(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let block_size = self.cache.block_size();
+        let head =
+            ((block_size - (offset % block_size as u64) as usize) % block_size).min(buf.len());
+        let (head_buf, rest) = buf.split_at_mut(head);
+        let body = rest.len() - rest.len() % block_size;
+        let (body_buf, tail_buf) = rest.split_at_mut(body);
+        self.cache.read_at(offset, head_buf)?;
+        if !body_buf.is_empty() {
+            let block = (offset + head as u64) / block_size as u64;
+            self.cache.device().read_blocks(block, body_buf)?;
+        }
+        self.cache
+            .read_at(offset + (head + body) as u64, tail_buf)?;
+        Ok(())
+    }
+
+    /// Clusters in use, from the allocation bitmap.
+    fn count_allocated(&self) -> Result<u32, FsError> {
+        let bytes = (self.cluster_count as u64).div_ceil(8);
+        let mut chunk = [0u8; 512];
+        let mut count = 0;
+        let mut offset = 0;
+        while offset < bytes {
+            let len = (bytes - offset).min(chunk.len() as u64) as usize;
+            self.read_extent(&self.bitmap, offset, &mut chunk[..len])?;
+            offset += len as u64;
+            // the bits after the last cluster
+            if offset == bytes && !self.cluster_count.is_multiple_of(8) {
+                chunk[len - 1] &= (1 << (self.cluster_count % 8)) - 1;
+            }
+            count += chunk[..len]
+                .iter()
+                .map(|byte| byte.count_ones())
+                .sum::<u32>();
+        }
+        Ok(count)
+    }
+
+    /// Call `f` on each entry of the directory at `extent` in turn, until it
+    /// returns false.
+    fn for_each_entry(
+        &self,
+        extent: &Extent,
+        mut f: impl FnMut(&[u8; ENTRY_SIZE]) -> bool,
+    ) -> Result<(), FsError> {
+        if extent.data_length == Some(0) {
+            return Ok(());
+        }
+        let cluster_size = self.cluster_size();
+        // the root directory has no data length and ends with its chain
+        let clusters = extent
+            .data_length
+            .map_or(self.cluster_count as u64, |len| len.div_ceil(cluster_size));
+        let mut cluster = Some(extent.first_cluster);
+        let mut index = 0;
+        while let Some(current) = cluster {
+            let base = self.cluster_offset(current);
+            for offset in (0..cluster_size).step_by(ENTRY_SIZE) {
+                let mut entry = [0; ENTRY_SIZE];
+                self.cache.read_at(base + offset, &mut entry)?;
+                if !f(&entry) {
+                    return Ok(());
+                }
+            }
+            index += 1;
+            cluster = match extent.data_length {
+                Some(_) if index < clusters => Some(self.cluster_at(extent, index)?),
+                Some(_) => None,
+                None => match self.next_cluster(current)? {
+                    Some(_) if index >= clusters => return Err(FsError::Corrupted),
+                    next => next,
+                },
+            };
+        }
+        Ok(())
+    }
+
+    /// Call `f` on each file and directory in the directory at `extent`,
+    /// until it returns false.
+    fn for_each_node(
+        &self,
+        extent: &Extent,
+        mut f: impl FnMut(Node) -> bool,
+    ) -> Result<(), FsError> {
+        let mut set: Vec<[u8; ENTRY_SIZE]> = Vec::new();
+        let mut remaining = 0;
+        self.for_each_entry(extent, |entry| {
+            if remaining > 0 {
+                set.push(*entry);
+                remaining -= 1;
+                if remaining == 0 {
+                    match self.parse_entry_set(&set) {
+                        Some(node) => return f(node),
+                        None => warn!("[kernel] exFAT: skipping a damaged directory entry set"),
+                    }
+                }
+                return true;
+            }
+            match entry[0] {
+                ENTRY_END => false,
+                ENTRY_FILE => {
+                    set.clear();
+                    set.push(*entry);
+                    remaining = entry[1];
+                    true
+                }
+                _ => true,
+            }
+        })?;
+        if remaining > 0 {
+            return Err(FsError::Corrupted);
+        }
+        Ok(())
+    }
+
+    /// Parse the entry set of a file: a file entry, a stream extension entry
+    /// and file name entries. `None` if it is damaged.
+    fn parse_entry_set(&self, set: &[[u8; ENTRY_SIZE]]) -> Option<Node> {
+        let secondary_count = set.len() - 1;
+        if !(2..=18).contains(&secondary_count) {
+            return None;
+        }
+        let checksum = u16::from_le_bytes([set[0][2], set[0][3]]);
+        if set_checksum(set) != checksum {
+            return None;
+        }
+        let file = unsafe { core::ptr::read_unaligned(set[0].as_ptr() as *const ExFatFileEntry) };
+        let stream =
+            unsafe { core::ptr::read_unaligned(set[1].as_ptr() as *const ExFatStreamEntry) };
+        if stream.entry_type != ENTRY_STREAM {
+            return None;
+        }
+        let name_length = stream.name_length as usize;
+        let name_entries = name_length.div_ceil(NAME_CHARS_PER_ENTRY);
+        if name_length == 0 || name_entries > secondary_count - 1 {
+            return None;
+        }
+        let mut name = Vec::with_capacity(name_length);
+        for entry in &set[2..2 + name_entries] {
+            if entry[0] != ENTRY_NAME {
+                return None;
+            }
+            name.extend(utf16(&entry[2..]));
+        }
+        name.truncate(name_length);
+        if self.upcase.name_hash(&name) != stream.name_hash {
+            return None;
+        }
+        let file_attributes = file.file_attributes;
+        let data = Extent {
+            first_cluster: stream.first_cluster,
+            data_length: Some(stream.data_length),
+            valid_data_length: stream.valid_data_length,
+            no_fat_chain: stream.flags & STREAM_NO_FAT_CHAIN != 0,
+        };
+        // an empty file may have no cluster at all
+        if !self.is_valid_extent(&data) {
+            return None;
+        }
+        Some(Node {
+            name,
+            is_dir: file_attributes & ATTR_DIRECTORY != 0,
+            data,
+        })
+    }
+
+    /// Find the file or directory at `path`, `None` for the root directory.
+    fn lookup(&self, path: &str) -> Result<Option<Node>, FsError> {
+        let mut node: Option<Node> = None;
+        for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            let extent = match &node {
+                None => self.root_extent(),
+                Some(node) if node.is_dir => node.data,
+                Some(_) => return Err(FsError::NotADirectory),
+            };
+            let name: Vec<u16> = component.encode_utf16().collect();
+            let hash = self.upcase.name_hash(&name);
+            let mut found = None;
+            self.for_each_node(&extent, |node| {
+                if self.upcase.name_hash(&node.name) == hash && self.upcase.eq(&node.name, &name) {
+                    found = Some(node);
+                    return false;
+                }
+                true
+            })?;
+            node = Some(found.ok_or(FsError::NotFound)?);
+        }
+        Ok(node)
+    }
 }
 
-impl<'a> AllocationBitmap<'a> {
-    pub fn is_allocated(&self, cluster: Cluster) -> bool {
-        let byte_index = (cluster / 8) as usize;
-        let bit_index = (cluster % 8) as u8;
-        (self.bitmap_data[byte_index] & (1 << bit_index)) != 0
+impl FileSystem for ExFat {
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let extent = match self.lookup(path)? {
+            None => self.root_extent(),
+            Some(node) if node.is_dir => node.data,
+            Some(_) => return Err(FsError::NotADirectory),
+        };
+        let mut entries = Vec::new();
+        self.for_each_node(&extent, |node| {
+            entries.push(DirEntry {
+                name: decode_name(&node.name),
+                is_dir: node.is_dir,
+                size: if node.is_dir {
+                    0
+                } else {
+                    node.data.data_length.unwrap_or(0)
+                },
+            });
+            true
+        })?;
+        Ok(entries)
     }
 
-    pub fn allocate_cluster(&mut self, cluster: Cluster) {
-        let byte_index = (cluster / 8) as usize;
-        let bit_index = (cluster % 8) as u8;
-        self.bitmap_data[byte_index] |= 1 << bit_index;
+    fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        match self.lookup(path)? {
+            Some(node) if !node.is_dir => self.read_extent(&node.data, offset, buf),
+            _ => Err(FsError::IsADirectory),
+        }
     }
+}
 
-    pub fn free_cluster(&mut self, cluster: Cluster) {
-        let byte_index = (cluster / 8) as usize;
-        let bit_index = (cluster % 8) as u8;
-        self.bitmap_data[byte_index] &= !(1 << bit_index);
+/// Read and check the boot region at byte `offset`: the boot sector, the
+/// extended boot sectors, the OEM parameters and a reserved sector, then a
+/// sector repeating their checksum.
+fn read_boot_region(cache: &BlockCache, offset: u64) -> Result<ExFatBootSector, FsError> {
+    let mut sector = [0; 512];
+    cache.read_at(offset, &mut sector)?;
+    let boot = ExFatBootSector::from_bytes(&sector);
+    if &boot.fs_name != b"EXFAT   " {
+        return Err(FsError::NotRecognized);
+    }
+    if !boot.is_valid() {
+        return Err(FsError::Corrupted);
+    }
+    let sector_size = 1usize << boot.bytes_per_sector_shift;
+    let mut region = vec![0; sector_size * 12];
+    cache.read_at(offset, &mut region)?;
+    let (sectors, checksums) = region.split_at(sector_size * 11);
+    let checksum = sectors
+        .iter()
+        .enumerate()
+        // VolumeFlags and PercentInUse change without the checksum changing
+        .filter(|&(i, _)| !matches!(i, 106 | 107 | 112))
+        .fold(0u32, |sum, (_, &byte)| {
+            sum.rotate_right(1).wrapping_add(byte as u32)
+        });
+    if checksums
+        .chunks_exact(4)
+        .any(|c| u32::from_le_bytes(c.try_into().unwrap()) != checksum)
+    {
+        warn!("[kernel] exFAT: boot region checksum mismatch");
+        return Err(FsError::Corrupted);
+    }
+    Ok(boot)
+}
+
+/// Checksum of the up-case table.
+fn table_checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |sum, &byte| {
+        sum.rotate_right(1).wrapping_add(byte as u32)
+    })
+}
+
+/// Checksum of a directory entry set, leaving out the checksum field itself.
+fn set_checksum(set: &[[u8; ENTRY_SIZE]]) -> u16 {
+    set.iter()
+        .flat_map(|entry| entry.iter())
+        .enumerate()
+        .filter(|&(i, _)| i != 2 && i != 3)
+        .fold(0u16, |sum, (_, &byte)| {
+            sum.rotate_right(1).wrapping_add(byte as u16)
+        })
+}
+
+fn utf16(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect()
+}
+
+fn decode_name(name: &[u16]) -> String {
+    char::decode_utf16(name.iter().copied())
+        .map(|ch| ch.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// Mount the first block device holding an exFAT volume at `/`.
+pub fn init() {
+    for (i, device) in super::virtio_blk::devices().iter().enumerate() {
+        let device: Arc<dyn StorageDevice + Send + Sync> = device.clone();
+        let cache = BlockCache::new(device, 256);
+        match ExFat::mount(cache) {
+            Ok(fs) => {
+                info!(
+                    "[kernel] mounted exFAT volume {:?} of block device {} at /",
+                    fs.label(),
+                    i
+                );
+                super::mount_root(Box::new(fs));
+                return;
+            }
+            Err(FsError::NotRecognized) => debug!("[kernel] block device {}: not exFAT", i),
+            Err(e) => warn!("[kernel] block device {}: exFAT: {:?}", i, e),
+        }
     }
 }
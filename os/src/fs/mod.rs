@@ -1,11 +1,19 @@
+pub mod cache;
 pub mod exfat;
 pub mod virtio_blk;
 
-use core::fmt::{Display, Formatter};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{
+    fmt::{Display, Formatter},
+    ops::Range,
+};
 
-#[derive(Debug)]
+use spin::Once;
+
+#[derive(Debug, Clone)]
 pub enum StorageError {
     /// An error occurred while sending a command to the device
+    #[allow(dead_code)]
     CommandFailed { command: u8, error_code: u32 },
 
     /// The requested block was out of range
@@ -18,8 +26,12 @@ pub enum StorageError {
     Timeout,
 
     /// Data read from the device was invalid or corrupted
+    #[allow(dead_code)]
     DataCorruption,
 
+    /// A buffer was not a whole number of blocks long
+    BadBufferLength { len: usize },
+
     /// An unknown error occurred
     Unknown,
 }
@@ -49,6 +61,9 @@ impl Display for StorageError {
             StorageError::DataCorruption => {
                 write!(f, "Data corruption detected during storage operation")
             }
+            StorageError::BadBufferLength { len } => {
+                write!(f, "Buffer of {} bytes is not a whole number of blocks", len)
+            }
             StorageError::Unknown => {
                 write!(f, "An unknown error occurred")
             }
@@ -56,7 +71,150 @@ impl Display for StorageError {
     }
 }
 
+/// A device storing fixed-size blocks.
+///
+/// Only single blocks have to be implemented; the rest loop over them unless
+/// a device can do better.
 pub trait StorageDevice {
     fn read_block(&self, block_num: u64, buffer: &mut [u8]) -> Result<(), StorageError>;
     fn write_block(&self, block_num: u64, buffer: &[u8]) -> Result<(), StorageError>;
+
+    /// Size of a block in bytes.
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    /// Number of blocks on the device, `u64::MAX` if unknown.
+    fn num_blocks(&self) -> u64 {
+        u64::MAX
+    }
+
+    /// Read consecutive blocks from `start` into `buffer`, a whole number of
+    /// blocks long.
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), StorageError> {
+        let block_size = self.block_size();
+        check_length(buffer.len(), block_size)?;
+        for (block_num, chunk) in (start..).zip(buffer.chunks_exact_mut(block_size)) {
+            self.read_block(block_num, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Write `buffer`, a whole number of blocks long, to consecutive blocks
+    /// from `start`.
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), StorageError> {
+        let block_size = self.block_size();
+        check_length(buffer.len(), block_size)?;
+        for (block_num, chunk) in (start..).zip(buffer.chunks_exact(block_size)) {
+            self.write_block(block_num, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Read consecutive blocks from `start` into `buffers` in turn, each a
+    /// whole number of blocks long.
+    fn read_blocks_vectored(
+        &self,
+        start: u64,
+        buffers: &mut [&mut [u8]],
+    ) -> Result<(), StorageError> {
+        let mut block_num = start;
+        for buffer in buffers {
+            self.read_blocks(block_num, buffer)?;
+            block_num += (buffer.len() / self.block_size()) as u64;
+        }
+        Ok(())
+    }
+
+    /// Write `buffers` in turn, each a whole number of blocks long, to
+    /// consecutive blocks from `start`.
+    fn write_blocks_vectored(&self, start: u64, buffers: &[&[u8]]) -> Result<(), StorageError> {
+        let mut block_num = start;
+        for buffer in buffers {
+            self.write_blocks(block_num, buffer)?;
+            block_num += (buffer.len() / self.block_size()) as u64;
+        }
+        Ok(())
+    }
+
+    /// Make sure what was written has reached stable storage.
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Tell the device the contents of `blocks` are no longer needed. This is
+    /// only a hint, and does nothing by default.
+    fn discard(&self, blocks: Range<u64>) -> Result<(), StorageError> {
+        let _ = blocks;
+        Ok(())
+    }
+}
+
+fn check_length(len: usize, block_size: usize) -> Result<(), StorageError> {
+    if len.is_multiple_of(block_size) {
+        Ok(())
+    } else {
+        Err(StorageError::BadBufferLength { len })
+    }
+}
+
+/// An entry of a directory.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    /// size in bytes, 0 for a directory
+    pub size: u64,
+}
+
+#[derive(Debug)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    /// The device does not hold this kind of file system
+    NotRecognized,
+    /// The on-disk structures make no sense
+    Corrupted,
+    Storage(StorageError),
+}
+
+impl Display for FsError {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "No such file or directory"),
+            FsError::NotADirectory => write!(f, "Not a directory"),
+            FsError::IsADirectory => write!(f, "Is a directory"),
+            FsError::NotRecognized => write!(f, "No recognized file system"),
+            FsError::Corrupted => write!(f, "File system structures are corrupted"),
+            FsError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<StorageError> for FsError {
+    fn from(e: StorageError) -> Self {
+        FsError::Storage(e)
+    }
+}
+
+/// A mounted file system, with `/`-separated absolute paths.
+pub trait FileSystem: Send + Sync {
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError>;
+    /// Read the file at `path` from byte `offset` into `buf`, returning how
+    /// many bytes were read: fewer than asked at the end of the file, and 0
+    /// past it.
+    fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+}
+
+static ROOT: Once<Box<dyn FileSystem>> = Once::new();
+
+/// Mount `fs` at `/`. Only the first file system mounted is kept.
+pub fn mount_root(fs: Box<dyn FileSystem>) {
+    ROOT.call_once(|| fs);
+}
+
+/// The file system mounted at `/`, if any.
+pub fn root() -> Option<&'static dyn FileSystem> {
+    ROOT.get().map(|fs| fs.as_ref())
 }
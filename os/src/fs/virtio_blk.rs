@@ -1,50 +1,551 @@
-use crate::mm;
-use spin::Mutex;
-use virtio_drivers::{device::blk::VirtIOBlk, transport::mmio::MmioTransport, Hal};
+//! VirtIO block devices, found by the `virtio,mmio` driver
+//!
+//! Requests are queued rather than run one at a time: a request for the
+//! sectors right after those of a queued one, in the same direction, is merged
+//! into it, and the device is given as many requests as its queue holds. They
+//! complete from the device interrupt, or from whoever waits on them when the
+//! device has none.
 
-pub struct VirtIOBlock(Mutex<VirtIOBlk<VirtIOBlockHal, MmioTransport>>);
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, vec, vec::Vec};
+use core::{
+    ptr::NonNull,
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    drivers::{plic, virtio},
+    mm::{
+        self,
+        allocator::{Frame, FrameAllocator, FRAME_ALLOCATOR},
+    },
+};
+use log::*;
+use riscv::interrupt::supervisor as interrupt;
+use spin::{Mutex, Once};
+use virtio_drivers::{
+    device::blk::{BlkReq, BlkResp, VirtIOBlk, SECTOR_SIZE},
+    transport::{mmio::MmioTransport, DeviceType},
+    BufferDirection, Hal,
+};
+
+use super::{check_length, StorageDevice, StorageError};
+
+/// Most sectors one merged request covers.
+const MAX_MERGE_SECTORS: usize = 64;
+const RUN_PAGES: usize = MAX_MERGE_SECTORS * SECTOR_SIZE / mm::PAGE_SIZE;
+/// PLIC priority of completion interrupts.
+const IRQ_PRIORITY: u32 = 1;
+
+pub struct VirtIOBlock {
+    inner: Mutex<Inner>,
+    /// whether completions come from an interrupt
+    has_irq: bool,
+}
+
+struct Inner {
+    blk: VirtIOBlk<VirtIOBlockHal, MmioTransport>,
+    /// merged requests not given to the device yet
+    pending: VecDeque<Box<Run>>,
+    /// requests the device is working on
+    #[allow(clippy::vec_box)]
+    in_flight: Vec<Box<Run>>,
+    /// requests are held back from the device while set, to merge them
+    plugged: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Read,
+    Write,
+}
+
+/// A request of a caller, done once the run it was merged into is.
+pub struct Request {
+    done: AtomicBool,
+    result: Mutex<Result<(), StorageError>>,
+    /// the sectors read, or to write
+    data: Mutex<Vec<u8>>,
+}
+
+impl Request {
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+}
+
+/// Adjacent requests merged into one for the device. Boxed, as the device
+/// keeps the addresses of `req` and `resp` while it works.
+struct Run {
+    op: Op,
+    start: u64,
+    sectors: usize,
+    buf: Frame,
+    req: BlkReq,
+    resp: BlkResp,
+    token: u16,
+    /// requests in the run, with their first sector relative to `start`
+    members: Vec<(Arc<Request>, usize)>,
+}
+
+impl Run {
+    fn buf(&mut self) -> &mut [u8] {
+        let len = self.sectors * SECTOR_SIZE;
+        unsafe { slice::from_raw_parts_mut(self.buf.virt_addr().as_ptr(), len) }
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.sectors as u64
+    }
+
+    fn finish(mut self, result: Result<(), StorageError>) {
+        let op = self.op;
+        let members = core::mem::take(&mut self.members);
+        let buf = self.buf();
+        for (request, offset) in members {
+            if op == Op::Read && result.is_ok() {
+                let mut data = request.data.lock();
+                let len = data.len();
+                data.copy_from_slice(&buf[offset * SECTOR_SIZE..][..len]);
+            }
+            *request.result.lock() = result.clone();
+            request.done.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// Block devices brought up by [`init`].
+static DEVICES: Once<Vec<Arc<VirtIOBlock>>> = Once::new();
 
 struct VirtIOBlockHal;
 unsafe impl Hal for VirtIOBlockHal {
     fn dma_alloc(
         pages: usize,
-        direction: virtio_drivers::BufferDirection,
-    ) -> (virtio_drivers::PhysAddr, core::ptr::NonNull<u8>) {
-        let frame = mm::allocator::FRAME_ALLOCATOR
-            .alloc(pages)
+        _direction: BufferDirection,
+    ) -> (virtio_drivers::PhysAddr, NonNull<u8>) {
+        let frame = FRAME_ALLOCATOR
+            .alloc_contiguous(pages)
             .expect("Failed to allocate DMA frame");
-        let paddr = frame.ptr.as_ptr() as usize;
-        let vaddr = frame.ptr;
+        let paddr = frame.phys_addr().as_usize();
+        let vaddr = NonNull::new(frame.virt_addr().as_ptr()).unwrap();
+        unsafe { core::ptr::write_bytes(vaddr.as_ptr(), 0, pages * mm::PAGE_SIZE) };
+        frame.leak();
         (paddr, vaddr)
     }
 
     unsafe fn dma_dealloc(
         paddr: virtio_drivers::PhysAddr,
-        vaddr: core::ptr::NonNull<u8>,
+        _vaddr: NonNull<u8>,
         pages: usize,
     ) -> i32 {
-        todo!()
+        let order = FrameAllocator::contiguous_order(pages);
+        drop(Frame::from_raw(paddr / mm::PAGE_SIZE, pages, order));
+        0
     }
 
-    unsafe fn mmio_phys_to_virt(
-        paddr: virtio_drivers::PhysAddr,
-        size: usize,
-    ) -> core::ptr::NonNull<u8> {
-        todo!()
+    unsafe fn mmio_phys_to_virt(paddr: virtio_drivers::PhysAddr, size: usize) -> NonNull<u8> {
+        NonNull::new(crate::map_mmio(paddr, size).as_ptr()).unwrap()
     }
 
+    /// Kernel memory is linearly mapped and the device is cache coherent, so
+    /// buffers are shared in place.
     unsafe fn share(
-        buffer: core::ptr::NonNull<[u8]>,
-        direction: virtio_drivers::BufferDirection,
+        buffer: NonNull<[u8]>,
+        _direction: BufferDirection,
     ) -> virtio_drivers::PhysAddr {
-        todo!()
+        mm::VirtAddr::from_ptr(buffer.as_ptr() as *const u8)
+            .to_phys()
+            .as_usize()
     }
 
     unsafe fn unshare(
-        paddr: virtio_drivers::PhysAddr,
-        buffer: core::ptr::NonNull<[u8]>,
-        direction: virtio_drivers::BufferDirection,
+        _paddr: virtio_drivers::PhysAddr,
+        _buffer: NonNull<[u8]>,
+        _direction: BufferDirection,
     ) {
-        todo!()
     }
 }
+
+impl VirtIOBlock {
+    pub fn new(
+        transport: MmioTransport,
+        has_irq: bool,
+    ) -> Result<VirtIOBlock, virtio_drivers::Error> {
+        let mut blk = VirtIOBlk::new(transport)?;
+        if has_irq {
+            blk.enable_interrupts();
+        } else {
+            blk.disable_interrupts();
+        }
+        Ok(VirtIOBlock {
+            inner: Mutex::new(Inner {
+                blk,
+                pending: VecDeque::new(),
+                in_flight: Vec::new(),
+                plugged: false,
+            }),
+            has_irq,
+        })
+    }
+
+    /// Run `f` with the lock held and interrupts disabled, as the interrupt
+    /// handler takes the lock too.
+    fn with_inner<R>(&self, f: impl FnOnce(&mut Inner) -> R) -> R {
+        interrupt::free(|| f(&mut self.inner.lock()))
+    }
+
+    /// Size of the device in sectors.
+    pub fn capacity(&self) -> u64 {
+        self.with_inner(|inner| inner.blk.capacity())
+    }
+
+    /// Queue a read of `count` sectors from `block`, without waiting.
+    pub fn read_blocks_nb(&self, block: u64, count: usize) -> Arc<Request> {
+        self.submit(Op::Read, block, vec![0; count * SECTOR_SIZE])
+    }
+
+    /// Queue a write of `data`, a whole number of sectors, at `block`, without
+    /// waiting.
+    pub fn write_blocks_nb(&self, block: u64, data: Vec<u8>) -> Arc<Request> {
+        assert!(data.len().is_multiple_of(SECTOR_SIZE));
+        self.submit(Op::Write, block, data)
+    }
+
+    /// Queue the requests `f` makes, and only then hand them to the device so
+    /// that adjacent ones are merged.
+    pub fn plugged<R>(&self, f: impl FnOnce() -> R) -> R {
+        let was_plugged = self.with_inner(|inner| core::mem::replace(&mut inner.plugged, true));
+        let ret = f();
+        if !was_plugged {
+            self.with_inner(|inner| {
+                inner.plugged = false;
+                inner.kick();
+            });
+        }
+        ret
+    }
+
+    fn submit(&self, op: Op, block: u64, data: Vec<u8>) -> Arc<Request> {
+        let sectors = data.len() / SECTOR_SIZE;
+        let request = Arc::new(Request {
+            done: AtomicBool::new(false),
+            result: Mutex::new(Ok(())),
+            data: Mutex::new(data),
+        });
+        self.with_inner(|inner| {
+            let mergeable = inner.pending.back_mut().filter(|run| {
+                run.op == op && run.end() == block && run.sectors + sectors <= MAX_MERGE_SECTORS
+            });
+            let run = match mergeable {
+                Some(run) => run,
+                None => match new_run(op, block) {
+                    Ok(run) => {
+                        inner.pending.push_back(run);
+                        inner.pending.back_mut().unwrap()
+                    }
+                    Err(e) => {
+                        *request.result.lock() = Err(e);
+                        request.done.store(true, Ordering::Release);
+                        return;
+                    }
+                },
+            };
+            // requests larger than a run still go in one piece
+            if run.sectors + sectors > MAX_MERGE_SECTORS {
+                run.buf = match FRAME_ALLOCATOR
+                    .alloc_pages((sectors * SECTOR_SIZE).div_ceil(mm::PAGE_SIZE), 0)
+                {
+                    Ok(frame) => frame,
+                    Err(_) => {
+                        inner.pending.pop_back();
+                        *request.result.lock() = Err(StorageError::Unknown);
+                        request.done.store(true, Ordering::Release);
+                        return;
+                    }
+                };
+            }
+            let offset = run.sectors;
+            run.sectors += sectors;
+            if op == Op::Write {
+                let data = request.data.lock();
+                run.buf()[offset * SECTOR_SIZE..].copy_from_slice(&data);
+            }
+            run.members.push((request.clone(), offset));
+            inner.kick();
+        });
+        request
+    }
+
+    /// Wait until `request` is done, and return its result.
+    pub fn wait(&self, request: &Request) -> Result<(), StorageError> {
+        while !request.is_done() {
+            self.poll();
+            if request.is_done() {
+                break;
+            }
+            if self.has_irq {
+                riscv::asm::wfi();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+        request.result.lock().clone()
+    }
+
+    /// Complete what the device is done with, and give it more. Requests
+    /// held back by [`plugged`](Self::plugged) go too, as whoever polls may be
+    /// waiting for one: an interrupt handler which interrupted the plugging
+    /// code, say.
+    pub fn poll(&self) {
+        self.with_inner(|inner| {
+            inner.blk.ack_interrupt();
+            inner.complete();
+            inner.submit();
+        });
+    }
+}
+
+/// An empty run with room for [`MAX_MERGE_SECTORS`].
+fn new_run(op: Op, start: u64) -> Result<Box<Run>, StorageError> {
+    let buf = FRAME_ALLOCATOR
+        .alloc_pages(RUN_PAGES, 0)
+        .map_err(|_| StorageError::Unknown)?;
+    Ok(Box::new(Run {
+        op,
+        start,
+        sectors: 0,
+        buf,
+        req: BlkReq::default(),
+        resp: BlkResp::default(),
+        token: 0,
+        members: Vec::new(),
+    }))
+}
+
+impl Inner {
+    /// Give the device pending runs, unless plugged.
+    fn kick(&mut self) {
+        if !self.plugged {
+            self.submit();
+        }
+    }
+
+    /// Give the device pending runs until its queue is full.
+    fn submit(&mut self) {
+        while let Some(mut run) = self.pending.pop_front() {
+            let run_ref = &mut *run;
+            let buf = unsafe {
+                slice::from_raw_parts_mut(
+                    run_ref.buf.virt_addr().as_ptr(),
+                    run_ref.sectors * SECTOR_SIZE,
+                )
+            };
+            let block = run_ref.start as usize;
+            let result = unsafe {
+                match run_ref.op {
+                    Op::Read => {
+                        self.blk
+                            .read_blocks_nb(block, &mut run_ref.req, buf, &mut run_ref.resp)
+                    }
+                    Op::Write => {
+                        self.blk
+                            .write_blocks_nb(block, &mut run_ref.req, buf, &mut run_ref.resp)
+                    }
+                }
+            };
+            match result {
+                Ok(token) => {
+                    run.token = token;
+                    self.in_flight.push(run);
+                }
+                Err(virtio_drivers::Error::QueueFull) => {
+                    self.pending.push_front(run);
+                    break;
+                }
+                Err(e) => {
+                    let start = run.start;
+                    run.finish(Err(storage_error(start, e)));
+                }
+            }
+        }
+    }
+
+    /// Finish every run the device is done with.
+    fn complete(&mut self) {
+        while let Some(token) = self.blk.peek_used() {
+            let Some(index) = self.in_flight.iter().position(|run| run.token == token) else {
+                warn!(
+                    "[kernel] virtio-blk: completion for unknown token {}",
+                    token
+                );
+                break;
+            };
+            let mut run = self.in_flight.swap_remove(index);
+            let run_ref = &mut *run;
+            let buf = unsafe {
+                slice::from_raw_parts_mut(
+                    run_ref.buf.virt_addr().as_ptr(),
+                    run_ref.sectors * SECTOR_SIZE,
+                )
+            };
+            let result = unsafe {
+                match run_ref.op {
+                    Op::Read => {
+                        self.blk
+                            .complete_read_blocks(token, &run_ref.req, buf, &mut run_ref.resp)
+                    }
+                    Op::Write => {
+                        self.blk
+                            .complete_write_blocks(token, &run_ref.req, buf, &mut run_ref.resp)
+                    }
+                }
+            };
+            let start = run.start;
+            run.finish(result.map_err(|e| storage_error(start, e)));
+        }
+    }
+}
+
+fn storage_error(block_num: u64, error: virtio_drivers::Error) -> StorageError {
+    match error {
+        virtio_drivers::Error::InvalidParam => StorageError::OutOfBounds { block_num },
+        virtio_drivers::Error::IoError => StorageError::HardwareFault,
+        virtio_drivers::Error::NotReady => StorageError::Timeout,
+        _ => StorageError::Unknown,
+    }
+}
+
+impl StorageDevice for VirtIOBlock {
+    fn read_block(&self, block_num: u64, buffer: &mut [u8]) -> Result<(), StorageError> {
+        if buffer.len() != SECTOR_SIZE {
+            return Err(StorageError::BadBufferLength { len: buffer.len() });
+        }
+        self.read_blocks(block_num, buffer)
+    }
+
+    fn write_block(&self, block_num: u64, buffer: &[u8]) -> Result<(), StorageError> {
+        if buffer.len() != SECTOR_SIZE {
+            return Err(StorageError::BadBufferLength { len: buffer.len() });
+        }
+        self.write_blocks(block_num, buffer)
+    }
+
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.capacity()
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), StorageError> {
+        self.read_blocks_vectored(start, &mut [buffer])
+    }
+
+    fn write_blocks(&self, start: u64, buffer: &[u8]) -> Result<(), StorageError> {
+        self.write_blocks_vectored(start, &[buffer])
+    }
+
+    /// Every buffer is queued before any is waited for, so that they merge
+    /// into as few requests as the device allows.
+    fn read_blocks_vectored(
+        &self,
+        start: u64,
+        buffers: &mut [&mut [u8]],
+    ) -> Result<(), StorageError> {
+        for buffer in buffers.iter() {
+            check_length(buffer.len(), SECTOR_SIZE)?;
+        }
+        let requests: Vec<_> = self.plugged(|| {
+            let mut block_num = start;
+            buffers
+                .iter()
+                .map(|buffer| {
+                    let count = buffer.len() / SECTOR_SIZE;
+                    let request = self.read_blocks_nb(block_num, count);
+                    block_num += count as u64;
+                    request
+                })
+                .collect()
+        });
+        for (buffer, request) in buffers.iter_mut().zip(&requests) {
+            self.wait(request)?;
+            buffer.copy_from_slice(&request.data.lock());
+        }
+        Ok(())
+    }
+
+    fn write_blocks_vectored(&self, start: u64, buffers: &[&[u8]]) -> Result<(), StorageError> {
+        for buffer in buffers {
+            check_length(buffer.len(), SECTOR_SIZE)?;
+        }
+        let requests: Vec<_> = self.plugged(|| {
+            let mut block_num = start;
+            buffers
+                .iter()
+                .map(|buffer| {
+                    let request = self.write_blocks_nb(block_num, buffer.to_vec());
+                    block_num += (buffer.len() / SECTOR_SIZE) as u64;
+                    request
+                })
+                .collect()
+        });
+        requests.iter().try_for_each(|request| self.wait(request))
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        loop {
+            self.poll();
+            // the device is waited on for a flush, which would take the
+            // completion of any other request for its own
+            let flushed = self.with_inner(|inner| {
+                (inner.pending.is_empty() && inner.in_flight.is_empty())
+                    .then(|| inner.blk.flush().map_err(|e| storage_error(0, e)))
+            });
+            if let Some(result) = flushed {
+                return result;
+            }
+            if self.has_irq {
+                riscv::asm::wfi();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+/// Bring up every VirtIO block device probed from the device tree, and take
+/// its interrupts if it has one.
+pub fn init() {
+    DEVICES.call_once(|| {
+        virtio::take(DeviceType::Block)
+            .into_iter()
+            .filter_map(|device| {
+                let irq = device.irq.filter(|_| plic::ready());
+                let blk = match VirtIOBlock::new(device.transport, irq.is_some()) {
+                    Ok(blk) => Arc::new(blk),
+                    Err(e) => {
+                        warn!("[kernel] {:?}: virtio block device: {:?}", device.node, e);
+                        return None;
+                    }
+                };
+                if let Some(irq) = irq {
+                    let handler = blk.clone();
+                    plic::register(irq, IRQ_PRIORITY, move || handler.poll());
+                }
+                info!(
+                    "[kernel] {:?}: virtio block device, {} sectors, irq {:?}",
+                    device.node,
+                    blk.capacity(),
+                    irq
+                );
+                Some(blk)
+            })
+            .collect()
+    });
+}
+
+/// Block devices brought up by [`init`].
+pub fn devices() -> &'static [Arc<VirtIOBlock>] {
+    DEVICES.get().map_or(&[], Vec::as_slice)
+}
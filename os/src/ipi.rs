@@ -0,0 +1,131 @@
+//! Inter-processor interrupts
+//!
+//! [`run_on`] queues a closure on the target harts and rings them with an SBI
+//! IPI; each runs its queue from the supervisor software interrupt.
+//! [`shootdown`] flushes the TLB of other harts through SBI RFENCE, which
+//! returns once the remote fences are done.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use riscv::{
+    interrupt::supervisor as interrupt,
+    register::{sie, sip},
+};
+use sbi_rt::HartMask;
+use spin::Mutex;
+
+use crate::percpu;
+
+/// A set of harts, bit `n` standing for hart `n`.
+pub type HartSet = usize;
+
+/// A closure queued on a hart, with the counter of harts done running it.
+struct Call {
+    f: Arc<dyn Fn() + Send + Sync>,
+    done: Arc<AtomicUsize>,
+}
+
+percpu! {
+    /// Closures other harts asked this hart to run.
+    static CALLS: Mutex<VecDeque<Call>> = Mutex::new(VecDeque::new());
+}
+
+/// Harts that take IPIs.
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Start taking IPIs on the calling hart.
+pub fn init_hart() {
+    let hartid = percpu::hart_id();
+    assert!(
+        hartid < HartSet::BITS as usize,
+        "hart id {hartid} too large"
+    );
+    unsafe { sie::set_ssoft() };
+    ONLINE.fetch_or(1 << hartid, Ordering::AcqRel);
+}
+
+/// Harts that take IPIs.
+pub fn online() -> HartSet {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Every online hart but the calling one.
+pub fn others() -> HartSet {
+    online() & !(1 << percpu::hart_id())
+}
+
+/// Run `f` on every online hart in `harts`, the calling one included if it is
+/// in the set, and wait until all of them are done.
+pub fn run_on(harts: HartSet, f: impl Fn() + Send + Sync + 'static) {
+    let this = percpu::hart_id();
+    let targets = harts & online() & !(1 << this);
+    let done = Arc::new(AtomicUsize::new(0));
+    if targets != 0 {
+        let f: Arc<dyn Fn() + Send + Sync> = Arc::new(f);
+        for hartid in iter(targets) {
+            let queue = CALLS
+                .remote_ptr(hartid)
+                .expect("online hart without a per-hart area");
+            let call = Call {
+                f: f.clone(),
+                done: done.clone(),
+            };
+            interrupt::free(|| unsafe { &*queue }.lock().push_back(call));
+        }
+        let ret = sbi_rt::send_ipi(HartMask::from_mask_base(targets, 0));
+        assert!(ret.is_ok(), "failed to send IPI: {:?}", ret);
+        if harts & (1 << this) != 0 {
+            f();
+        }
+        // two harts may be waiting on each other with interrupts disabled
+        while done.load(Ordering::Acquire) < targets.count_ones() as usize {
+            run_pending();
+            core::hint::spin_loop();
+        }
+    } else if harts & (1 << this) != 0 {
+        f();
+    }
+}
+
+/// Run `f` on every other online hart and wait until all of them are done.
+pub fn run_on_others(f: impl Fn() + Send + Sync + 'static) {
+    run_on(others(), f)
+}
+
+/// Supervisor software interrupt: run what other harts queued.
+pub fn handle_interrupt() {
+    unsafe { sip::clear_ssoft() };
+    run_pending();
+}
+
+fn run_pending() {
+    loop {
+        let Some(call) = CALLS.with(|calls| calls.lock().pop_front()) else {
+            break;
+        };
+        (call.f)();
+        call.done.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+/// Drop the translations of `[start, start + size)` from the TLB of `harts`,
+/// for `asid` only or for every address space when it is `None`. A `size` of
+/// `usize::MAX` flushes everything.
+pub fn shootdown(harts: HartSet, start: usize, size: usize, asid: Option<usize>) {
+    let harts = harts & online();
+    if harts == 0 {
+        return;
+    }
+    let mask = HartMask::from_mask_base(harts, 0);
+    let ret = match asid {
+        Some(asid) => sbi_rt::remote_sfence_vma_asid(mask, start, size, asid),
+        None => sbi_rt::remote_sfence_vma(mask, start, size),
+    };
+    assert!(ret.is_ok(), "remote sfence.vma failed: {:?}", ret);
+}
+
+/// Hart ids in `harts`.
+pub fn iter(harts: HartSet) -> impl Iterator<Item = usize> {
+    (0..HartSet::BITS as usize).filter(move |hartid| harts & (1 << hartid) != 0)
+}
@@ -1,20 +1,22 @@
 //! The panic handler
 
-use crate::sbi::shutdown;
+use crate::{console::print_unlocked, dmesg, sbi::shutdown};
 use core::panic::PanicInfo;
 
 #[panic_handler]
 /// panic handler
 fn panic(info: &PanicInfo) -> ! {
     if let Some(location) = info.location() {
-        println!(
-            "[kernel] Panicked at {}:{} {}",
+        print_unlocked(format_args!(
+            "[kernel] Panicked at {}:{} {}\n",
             location.file(),
             location.line(),
             info.message()
-        );
+        ));
     } else {
-        println!("[kernel] Panicked: {}", info.message());
+        print_unlocked(format_args!("[kernel] Panicked: {}\n", info.message()));
     }
+    print_unlocked(format_args!("[kernel] ---- kernel log ----\n"));
+    dmesg::for_each(|_, text| print_unlocked(format_args!("{}\n", text)));
     shutdown()
 }
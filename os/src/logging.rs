@@ -1,13 +1,116 @@
 //! Global logger
+//!
+//! Records are filtered by target, the module path with the crate name left
+//! out, from a spec such as `info,mm=trace,fs=warn`: bare levels set the
+//! default and `target=level` entries the level of a module and those below
+//! it. The spec comes from the `LOG` environment variable at build time, then
+//! from `log=` on the kernel command line and the `log` shell command.
+//!
+//! Records are printed and kept in the [`dmesg`] ring buffer.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
 
 use log::{Level, LevelFilter, Log, Metadata, Record};
+use riscv::interrupt::supervisor as interrupt;
+use spin::RwLock;
+
+use crate::{dmesg, percpu, time};
+
+/// Module path prefix every target of this kernel starts with.
+const CRATE_PREFIX: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+
+struct Filter {
+    default: LevelFilter,
+    /// `(target, level)`, the longest matching target wins
+    targets: Vec<(String, LevelFilter)>,
+}
+
+#[derive(Debug)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bad log filter entry {:?}", self.0)
+    }
+}
+
+impl Filter {
+    const fn new(default: LevelFilter) -> Filter {
+        Filter {
+            default,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Apply the entries of `spec` over the current settings.
+    fn update(&mut self, spec: &str) -> Result<(), ParseError> {
+        let mut updated = Filter {
+            default: self.default,
+            targets: self.targets.clone(),
+        };
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let bad = || ParseError(entry.to_string());
+            match entry.split_once('=') {
+                Some((target, level)) => {
+                    let target = target.trim().trim_start_matches(CRATE_PREFIX);
+                    let level = level.trim().parse().map_err(|_| bad())?;
+                    if target.is_empty() {
+                        return Err(bad());
+                    }
+                    updated.targets.retain(|(t, _)| t != target);
+                    updated.targets.push((target.to_string(), level));
+                }
+                None => updated.default = entry.parse().map_err(|_| bad())?,
+            }
+        }
+        *self = updated;
+        Ok(())
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let target = target.trim_start_matches(CRATE_PREFIX);
+        self.targets
+            .iter()
+            .filter(|(t, _)| {
+                target
+                    .strip_prefix(t.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(t, _)| t.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    /// The most verbose level anything is logged at.
+    fn max(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default.as_str().to_ascii_lowercase())?;
+        for (target, level) in &self.targets {
+            write!(f, ",{}={}", target, level.as_str().to_ascii_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+static FILTER: RwLock<Filter> = RwLock::new(Filter::new(LevelFilter::Off));
 
 /// a simple logger
 struct SimpleLogger;
 
 impl Log for SimpleLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.read().level_for(metadata.target())
     }
     fn log(&self, record: &Record) {
         if !self.enabled(record.metadata()) {
@@ -20,10 +123,42 @@ impl Log for SimpleLogger {
             Level::Debug => 32, // Green
             Level::Trace => 90, // BrightBlack
         };
+        // the clock runs once the timebase frequency is known
+        let (secs, micros) = if time::frequency() != 0 {
+            let since_boot = time::Instant::now().since_boot();
+            (since_boot.as_secs(), since_boot.subsec_micros())
+        } else {
+            (0, 0)
+        };
+        let hart = if percpu::ready() {
+            percpu::hart_id() as isize
+        } else {
+            -1
+        };
+        let module = record
+            .module_path()
+            .unwrap_or("?")
+            .trim_start_matches(CRATE_PREFIX);
+        let line = record.line().unwrap_or(0);
+        dmesg::write(format_args!(
+            "[{:>5}.{:06} {} {:>5} {}:{}] {}",
+            secs,
+            micros,
+            hart,
+            record.level(),
+            module,
+            line,
+            record.args(),
+        ));
         println!(
-            "\u{1B}[{}m[{:>5}] {}\u{1B}[0m",
+            "\u{1B}[{}m[{:>5}.{:06} {} {:>5} {}:{}] {}\u{1B}[0m",
             color,
+            secs,
+            micros,
+            hart,
             record.level(),
+            module,
+            line,
             record.args(),
         );
     }
@@ -34,12 +169,36 @@ impl Log for SimpleLogger {
 pub fn init() {
     static LOGGER: SimpleLogger = SimpleLogger;
     log::set_logger(&LOGGER).unwrap();
-    log::set_max_level(match option_env!("LOG") {
-        Some("ERROR") => LevelFilter::Error,
-        Some("WARN") => LevelFilter::Warn,
-        Some("INFO") => LevelFilter::Info,
-        Some("DEBUG") => LevelFilter::Debug,
-        Some("TRACE") => LevelFilter::Trace,
-        _ => LevelFilter::Off,
-    });
+    if let Err(e) = configure(option_env!("LOG").unwrap_or("off")) {
+        println!("[kernel] LOG: {}", e);
+    }
+}
+
+/// Apply the entries of `spec` over the current filter.
+pub fn configure(spec: &str) -> Result<(), ParseError> {
+    // a record logged by an interrupt handler on this hart would wait for the
+    // lock forever
+    interrupt::free(|| {
+        let mut filter = FILTER.write();
+        filter.update(spec)?;
+        log::set_max_level(filter.max());
+        Ok(())
+    })
+}
+
+/// Apply `log=` of the kernel command line.
+pub fn configure_from_bootargs(bootargs: &str) {
+    for spec in bootargs
+        .split_whitespace()
+        .filter_map(|arg| arg.strip_prefix("log="))
+    {
+        if let Err(e) = configure(spec) {
+            log::warn!("[kernel] bootargs: {}", e);
+        }
+    }
+}
+
+/// The current filter, as a spec [`configure`] takes.
+pub fn spec() -> String {
+    interrupt::free(|| FILTER.read().to_string())
 }
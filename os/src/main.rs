@@ -7,42 +7,64 @@
 //!
 //! We then call [`println!`] to display `Hello, world!`.
 
-#![feature(naked_functions)]
 #![feature(const_trait_impl)]
 #![feature(alloc_error_handler)]
 #![deny(missing_docs)]
 #![no_std]
 #![no_main]
 
+use alloc::{sync::Arc, vec, vec::Vec};
 use core::{
-    arch::asm,
-    mem,
-    ptr::addr_of,
-    sync::atomic::{AtomicUsize, Ordering},
-    usize,
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 use log::*;
 
+extern crate alloc;
+
 #[macro_use]
 mod console;
+mod dmesg;
+mod drivers;
+mod dt;
 mod fs;
+mod ipi;
 mod lang_items;
 mod logging;
 mod mm;
+#[macro_use]
+mod percpu;
 mod sbi;
+mod shell;
+mod time;
+mod trap;
 
 #[path = "boards/qemu.rs"]
 mod board;
 
-const BOOT_STACK_SIZE: usize = 4096;
+const BOOT_STACK_SIZE: usize = 64 * 1024; // 64KiB
 #[link_section = ".bss.stack"]
 static mut BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 
-const KERNEL_HEAP_SIZE: usize = 128 * 1024; // 128KiB
+const KERNEL_HEAP_SIZE: usize = 1024 * 1024; // 1MiB
 #[link_section = ".bss.uninit"]
 static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 #[global_allocator]
-static KERNEL_HEAP: LockedHeap<32> = LockedHeap::empty();
+static KERNEL_HEAP: KernelHeap = KernelHeap(LockedHeap::empty());
+
+/// The kernel heap, locked with interrupts disabled so that interrupt handlers
+/// may allocate.
+struct KernelHeap(LockedHeap<32>);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        riscv::interrupt::supervisor::free(|| self.0.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        riscv::interrupt::supervisor::free(|| self.0.dealloc(ptr, layout))
+    }
+}
 
 #[alloc_error_handler]
 /// panic when heap allocation error occurs
@@ -51,20 +73,25 @@ pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
 }
 
 use buddy_system_allocator::LockedHeap;
-use mm::{allocator::FRAME_ALLOCATOR, Address, AlignSize, RootPageTable, Sv39};
+use mm::{
+    allocator::{Frame, FRAME_ALLOCATOR},
+    AddressSpace, Align4K, AlignSize, PageTableEntryFlags, PagingMode, PhysAddr, PhysRange,
+    RootPageTable, Sv39, Sv48, Sv57, VirtAddr, VirtRange, PHYS_VIRT_OFFSET,
+};
 
 /// 内核入口。
 ///
 /// # Safety
 ///
 /// 裸函数。
-#[naked]
+#[unsafe(naked)]
 #[no_mangle]
 #[link_section = ".text.boot"]
 unsafe extern "C" fn _start() -> ! {
     core::arch::naked_asm!("
         mv      s0, a0                  // save hartid
         mv      s1, a1                  // save DTB pointer
+        mv      tp, zero                // no per-hart area yet
         la      sp, {boot_stack}
         li      t0, {boot_stack_size}
         add     sp, sp, t0              // setup boot stack
@@ -90,30 +117,53 @@ unsafe extern "C" fn _start() -> ! {
     )
 }
 
+/// 副核入口。SBI HSM 以物理地址启动副核，`a0` 为 hartid，`a1` 为栈顶物理地址。
+///
+/// # Safety
+///
+/// 裸函数。
+#[unsafe(naked)]
 unsafe extern "C" fn _hart_start(hartid: usize, stack_top: usize) -> ! {
-    asm!(
-        "mv sp, {stack_top}",
-        "mv a0, {hartid}",
-        "mv a1, {stack_top}",
-        "call {main}",
-        stack_top = in(reg) stack_top,
-        hartid = in(reg) hartid,
-        main = sym rust_main,
-        options(noreturn),
+    core::arch::naked_asm!("
+        mv      s0, a0                  // save hartid
+        mv      sp, a1                  // physical stack top
+        mv      tp, zero                // no per-hart area yet
+
+        call    {init_mmu}              // enable MMU with the boot page table
+
+        li      s2, {phys_virt_offset}  // fix up virtual high address
+        add     sp, sp, s2
+
+        mv      a0, s0
+        la      a1, {entry}
+        add     a1, a1, s2
+        jalr    a1                      // call secondary_main(hartid)
+        j       .",
+        phys_virt_offset = const PHYS_VIRT_OFFSET,
+        init_mmu = sym init_mmu,
+        entry = sym secondary_main,
     )
 }
 
 /// virtual address of the kernel
-const PHYS_VIRT_OFFSET: usize = 0xffff_ffc0_0000_0000;
 const KERNEL_VIRT_BASE: usize = 0xffff_ffc0_8000_0000;
-const VIRT_ADDR: Address = Address::new(KERNEL_VIRT_BASE);
+const VIRT_ADDR: VirtAddr = VirtAddr::new(KERNEL_VIRT_BASE);
 const KERNEL_PHYS_BASE: usize = 0x80000000;
-const PHY_ADDR: Address = Address::new(KERNEL_PHYS_BASE);
-const KERNEL_START: usize = 0x80200000;
+const PHY_ADDR: PhysAddr = PhysAddr::new(KERNEL_PHYS_BASE);
+/// RAM the boot page table maps in the high half.
+const BOOT_MAPPED: (usize, usize) = (
+    KERNEL_PHYS_BASE,
+    KERNEL_PHYS_BASE + AlignSize::Page1G as usize,
+);
 
 fn init_boot_page_table() {
     unsafe {
-        let _ = ROOT_PAGE_TABLE.map(PHY_ADDR, PHY_ADDR, AlignSize::Page1G, mm::KERNEL_PTE_FLAGS);
+        let _ = ROOT_PAGE_TABLE.map(
+            VirtAddr::new(KERNEL_PHYS_BASE),
+            PHY_ADDR,
+            AlignSize::Page1G,
+            mm::KERNEL_PTE_FLAGS,
+        );
         let _ = ROOT_PAGE_TABLE.map(VIRT_ADDR, PHY_ADDR, AlignSize::Page1G, mm::KERNEL_PTE_FLAGS);
     }
 }
@@ -126,6 +176,121 @@ fn init_mmu() {
 #[link_section = ".pte.entry"]
 static ROOT_PAGE_TABLE: RootPageTable<Sv39> = RootPageTable::zero();
 
+/// Kernel address space once running in the high half: W^X per section and no
+/// identity mapping, in the deepest paging mode the hart supports.
+static KERNEL_PAGE_TABLE: spin::Once<&'static dyn AddressSpace> = spin::Once::new();
+
+/// Build the kernel address space and switch to it. `image` is the physical
+/// range of the firmware and kernel image, which is left out of the linear map
+/// of `memory` as the kernel sections are mapped on their own.
+fn init_kernel_page_table(memory: &[(usize, usize)], image: (usize, usize)) {
+    let mode = PagingMode::probe();
+    info!("[kernel] paging mode {:?}", mode);
+    let table: &'static dyn AddressSpace = match mode {
+        PagingMode::Sv39 => RootPageTable::<Sv39>::alloc().map(|t| t as _),
+        PagingMode::Sv48 => RootPageTable::<Sv48>::alloc().map(|t| t as _),
+        PagingMode::Sv57 => RootPageTable::<Sv57>::alloc().map(|t| t as _),
+    }
+    .expect("failed to allocate kernel page table");
+
+    let map = |name: &str, start: usize, end: usize, flags: PageTableEntryFlags| {
+        unsafe {
+            table.map_range(
+                VirtRange::new(VirtAddr::new(start), VirtAddr::new(end)),
+                VirtAddr::new(start).to_phys(),
+                flags,
+            )
+        }
+        .unwrap_or_else(|e| panic!("failed to map kernel {name}: {e:?}"));
+    };
+
+    let sections: [(&str, usize, usize, PageTableEntryFlags); 4] = [
+        (
+            ".text",
+            _stext as *const () as usize,
+            _etext as *const () as usize,
+            mm::KERNEL_TEXT_FLAGS,
+        ),
+        (
+            ".rodata",
+            _srodata as *const () as usize,
+            _erodata as *const () as usize,
+            mm::KERNEL_RODATA_FLAGS,
+        ),
+        (
+            ".data",
+            _sdata as *const () as usize,
+            _edata as *const () as usize,
+            mm::KERNEL_DATA_FLAGS,
+        ),
+        // the boot stack sits in front of `_sbss`
+        (
+            ".bss",
+            boot_stack as *const () as usize,
+            _ebss as *const () as usize,
+            mm::KERNEL_DATA_FLAGS,
+        ),
+    ];
+    for (name, start, end, flags) in sections {
+        map(name, start, end, flags);
+    }
+
+    for &(start, end) in memory {
+        for (start, end) in [(start, end.min(image.0)), (start.max(image.1), end)] {
+            if start < end {
+                map(
+                    "memory",
+                    start + PHYS_VIRT_OFFSET,
+                    end + PHYS_VIRT_OFFSET,
+                    mm::KERNEL_DATA_FLAGS,
+                );
+            }
+        }
+    }
+    table.active(0);
+    KERNEL_PAGE_TABLE.call_once(|| table);
+}
+
+/// Make the device tree blob at physical `[start, start + size)` read-only in
+/// the linear map: it has been parsed into [`dt::tree`], and is only kept out
+/// of the frame allocator.
+fn protect_dtb(start: usize, size: usize) {
+    let table = KERNEL_PAGE_TABLE.get().expect("no kernel page table");
+    let start = PhysAddr::new(start).to_virt();
+    let range = VirtRange::new(
+        start.align_down::<Align4K>().unaligned(),
+        (start + size).align_up::<Align4K>().unaligned(),
+    );
+    if let Err(e) = unsafe { table.protect(range, PageTableEntryFlags::R) } {
+        warn!("[kernel] failed to map the device tree read-only: {e:?}");
+    }
+}
+
+/// Map the device registers at physical `[start, start + size)` into the kernel
+/// address space, at their linear-map address which RAM leaves unused.
+fn map_mmio(start: usize, size: usize) -> VirtAddr {
+    let table = KERNEL_PAGE_TABLE
+        .get()
+        .expect("device mapped before the kernel page table");
+    let range = PhysRange::new(PhysAddr::new(start), PhysAddr::new(start + size));
+    // devices may share a page
+    for page in range.pages() {
+        let va = page.to_virt().unaligned();
+        let result = unsafe {
+            table.map_range(
+                VirtRange::new(va, va + mm::PAGE_SIZE),
+                page.unaligned(),
+                mm::KERNEL_DATA_FLAGS,
+            )
+        };
+        match result {
+            Ok(()) | Err(mm::Error::AlreadyMapped) => {}
+            Err(e) => panic!("failed to map device at {start:#x}: {e:?}"),
+        }
+    }
+    PhysAddr::new(start).to_virt()
+}
+
 static BOOT_HART: AtomicUsize = AtomicUsize::new(usize::MAX);
 
 fn set_boot_hart(hartid: usize) {
@@ -136,10 +301,126 @@ fn is_boot_hart(hartid: usize) -> bool {
     BOOT_HART.load(Ordering::Acquire) == hartid
 }
 
+/// Stack size of every secondary hart, in pages.
+const HART_STACK_PAGES: usize = 16;
+/// How long started harts have to come online.
+const HART_START_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+/// Harts that reached [`secondary_main`], or [`rust_main`] for the boot hart.
+/// A [`HartSet`](ipi::HartSet).
+static HARTS_ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocate the stacks of the secondary harts. They must come from RAM the boot
+/// page table maps, both identically and in the high half, as `_hart_start`
+/// runs on them while switching to virtual addresses.
+///
+/// The lowest page of every stack is unmapped from the kernel page table, so
+/// that an overflow faults instead of running into other memory.
+fn alloc_hart_stacks(harts: &[usize], boot_hart: usize) -> Vec<(usize, Frame)> {
+    let table = KERNEL_PAGE_TABLE.get().expect("no kernel page table");
+    harts
+        .iter()
+        .filter(|&&hartid| hartid != boot_hart)
+        .map(|&hartid| {
+            let stack = FRAME_ALLOCATOR
+                .alloc_pages(HART_STACK_PAGES, 0)
+                .expect("failed to allocate hart stack");
+            unsafe { table.unmap(guard_page(&stack)) }
+                .expect("failed to unmap hart stack guard page");
+            (hartid, stack)
+        })
+        .collect()
+}
+
+/// Lowest page of a hart stack.
+fn guard_page(stack: &Frame) -> VirtRange {
+    let start = stack.virt_addr().unaligned();
+    VirtRange::new(start, start + mm::PAGE_SIZE)
+}
+
+/// Start every secondary hart with its stack, then wait until all of them
+/// check in.
+fn start_secondary_harts(stacks: Vec<(usize, Frame)>) {
+    let entry = _hart_start as *const () as usize - PHYS_VIRT_OFFSET;
+    let mut started: ipi::HartSet = 0;
+    for (hartid, stack) in stacks {
+        let stack_top = stack.phys_addr().as_usize() + stack.pages() * mm::PAGE_SIZE;
+        let ret = sbi_rt::hart_start(hartid, entry, stack_top);
+        if ret.is_ok() {
+            // the stack belongs to the hart from now on
+            stack.leak();
+            started |= 1 << hartid;
+        } else {
+            warn!("[kernel] failed to start hart {hartid}: {:?}", ret);
+            // the frames go back to the allocator, mapped as they were
+            let table = KERNEL_PAGE_TABLE.get().expect("no kernel page table");
+            unsafe {
+                table.remap(
+                    guard_page(&stack),
+                    stack.phys_addr().unaligned(),
+                    mm::KERNEL_DATA_FLAGS,
+                )
+            }
+            .expect("failed to map hart stack guard page back");
+        }
+    }
+
+    // a one-shot timer ends the wait, and is cancelled once every hart is in
+    let start = time::Instant::now();
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let timer = time::add_timer_after(HART_START_TIMEOUT, {
+        let timed_out = timed_out.clone();
+        move || timed_out.store(true, Ordering::Release)
+    });
+    while HARTS_ONLINE.load(Ordering::Acquire) & started != started {
+        if timed_out.load(Ordering::Acquire) {
+            let online = HARTS_ONLINE.load(Ordering::Acquire);
+            for hartid in ipi::iter(started & !online) {
+                warn!("[kernel] hart {hartid} started but did not come online in time");
+            }
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    time::cancel_timer(timer);
+    info!(
+        "[kernel] {} harts online after {:?}",
+        HARTS_ONLINE.load(Ordering::Acquire).count_ones(),
+        start.elapsed()
+    );
+}
+
+/// Rust entry of secondary harts, running on the boot page table.
+extern "C" fn secondary_main(hartid: usize) -> ! {
+    debug_assert!(!is_boot_hart(hartid));
+    percpu::init_secondary(hartid);
+    KERNEL_PAGE_TABLE
+        .get()
+        .expect("secondary hart started before the kernel page table")
+        .active(0);
+    trap::init();
+    time::init_hart();
+    ipi::init_hart();
+    drivers::plic::init_hart();
+    unsafe { riscv::interrupt::supervisor::enable() };
+    debug!("[kernel] hart {hartid} online");
+    HARTS_ONLINE.fetch_or(1 << hartid, Ordering::AcqRel);
+    loop {
+        riscv::asm::wfi();
+        idle();
+    }
+}
+
+/// Background work for harts with nothing better to do, outside of interrupt
+/// handlers: secondary harts between interrupts, and the boot hart while the
+/// monitor waits for input.
+fn idle() {
+    fs::cache::writeback_if_due();
+}
+
 fn init_bss() {
     unsafe {
-        let sbss_addr = _sbss as usize;
-        let ebss_addr = _ebss as usize;
+        let sbss_addr = _sbss as *const () as usize;
+        let ebss_addr = _ebss as *const () as usize;
         let bss_size = ebss_addr - sbss_addr;
         let bss_ptr = sbss_addr as *mut u8;
         core::slice::from_raw_parts_mut(bss_ptr, bss_size).fill(0);
@@ -149,6 +430,7 @@ fn init_bss() {
 fn init_heap() {
     unsafe {
         KERNEL_HEAP
+            .0
             .lock()
             .init(&raw const HEAP_SPACE as usize, KERNEL_HEAP_SIZE)
     }
@@ -158,7 +440,7 @@ macro_rules! print_section_range {
     ($section_name:expr, $start:ident, $end:ident) => {
         debug!(
             "[kernel] {} [{:#20x}, {:#20x})",
-            $section_name, $start as usize, $end as usize
+            $section_name, $start as *const () as usize, $end as *const () as usize
         );
     };
 }
@@ -174,16 +456,41 @@ fn print_sections_range() {
 #[no_mangle]
 extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
     init_bss();
+    percpu::init_boot(hartid);
+    set_boot_hart(hartid);
+    HARTS_ONLINE.fetch_or(1 << hartid, Ordering::AcqRel);
+    trap::init();
     logging::init();
     print_sections_range();
     init_heap();
-    let BoardInfo {
-        smp,
-        frequency,
-        uart: _uart,
-        memory,
-        memory_count,
-    } = BoardInfo::parse(dtb_pa);
+    let tree =
+        unsafe { dt::init(PhysAddr::new(dtb_pa).to_virt().as_ptr()) }.expect("failed to parse dtb");
+    if let Some(bootargs) = tree
+        .find_node("/chosen")
+        .and_then(|chosen| chosen.property("bootargs"))
+        .and_then(dt::Property::as_str)
+    {
+        logging::configure_from_bootargs(bootargs);
+    }
+    let cpus = cpus(tree);
+    let smp = cpus.len();
+    // hart ids index a HartSet, so larger ones cannot be brought up
+    let harts: Vec<usize> = cpus
+        .iter()
+        .map(|cpu| cpu.id)
+        .filter(|&id| {
+            let fits = id < ipi::HartSet::BITS as usize;
+            if !fits {
+                warn!("[kernel] hart {id}: id too large, not brought up");
+            }
+            fits
+        })
+        .collect();
+    let frequency = timebase_frequency(tree);
+    let sstc_harts = cpus.iter().filter(|cpu| cpu.sstc).count();
+    time::init(frequency, sstc_harts == smp);
+    ipi::init_hart();
+    unsafe { riscv::interrupt::supervisor::enable() };
 
     info!(
         r"
@@ -203,35 +510,73 @@ extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
 ------------------------------------------------"
     );
 
-    for (i, (start, end)) in memory.iter().take(memory_count).enumerate() {
+    // SBI firmware sits in front of the kernel image
+    let mut excluded = vec![
+        (
+            KERNEL_PHYS_BASE,
+            _ekernel as *const () as usize - PHYS_VIRT_OFFSET,
+        ),
+        (dtb_pa, dtb_pa + tree.total_size()),
+    ];
+    excluded.extend(reserved_memory(tree));
+    for (i, (start, end)) in excluded.iter().enumerate() {
+        info!("reserved region {i:8} [{start:#20x}, {end:#20x})");
+    }
+
+    // page tables for the linear map come from the RAM the boot page table
+    // already maps, the rest is handed over once it is mapped too
+    let memory: Vec<(usize, usize)> = tree
+        .find_device_type("memory")
+        .flat_map(|node| node.reg())
+        .collect();
+    let memory = &memory[..];
+    let excluded = &excluded[..];
+    for (i, &(start, end)) in memory.iter().enumerate() {
         info!(
             r"memory region {i:10} [{start:#20x}, {end:#20x})",
             i = i,
             start = start,
             end = end
         );
-        let kernel_end = KERNEL_START + 0x4000000;
-        FRAME_ALLOCATOR.init(kernel_end, *end - kernel_end);
+        let result = FRAME_ALLOCATOR.add_zone(start, end).and_then(|_| {
+            FRAME_ALLOCATOR.add_free(start.max(BOOT_MAPPED.0), end.min(BOOT_MAPPED.1), excluded)
+        });
+        if let Err(e) = result {
+            warn!("memory region {i} not usable: {e:?}");
+        }
+    }
+    init_kernel_page_table(memory, excluded[0]);
+    protect_dtb(dtb_pa, tree.total_size());
+    // secondary harts reach their stack and per-hart area on the boot page table
+    let hart_stacks = alloc_hart_stacks(&harts, hartid);
+    percpu::alloc_areas(&harts);
+    for &(start, end) in memory {
+        let outside = [
+            (start, end.min(BOOT_MAPPED.0)),
+            (start.max(BOOT_MAPPED.1), end),
+        ];
+        for (start, end) in outside {
+            let _ = FRAME_ALLOCATOR.add_free(start, end, excluded);
+        }
+    }
+    for (i, zone) in FRAME_ALLOCATOR.stats().iter().enumerate() {
+        info!(
+            "zone {i} [{:#x}, {:#x}) {} frames free, {} used",
+            zone.start,
+            zone.end,
+            zone.free_frames,
+            zone.used_frames()
+        );
     }
 
-    // for i in 0..smp {
-    //     let frame = FRAME_ALLOCATOR
-    //         .alloc(0x800000)
-    //         .expect("failed to allocate stack frame");
-    //     if i != hartid {
-    //         let hart_stack = frame.ptr.as_ptr() as usize;
-    //         sbi_rt::hart_start(i, KERNEL_START, hart_stack);
-    //     }
-    //     mem::forget(frame);
-    // }
-
-    // for _ in 0..100_000_000 {
-    //     unsafe {
-    //         asm!("nop");
-    //     }
-    // }
-
-    sbi::shutdown();
+    drivers::probe_all(tree);
+    fs::virtio_blk::init();
+    fs::exfat::init();
+
+    start_secondary_harts(hart_stacks);
+
+    console::set_idle(idle);
+    shell::run();
 }
 
 extern "C" {
@@ -245,78 +590,54 @@ extern "C" {
     fn _ebss();
     fn _ekernel();
     fn boot_stack();
-    fn boot_stack_top();
 }
 
-struct BoardInfo {
-    smp: usize,
-    frequency: u64,
-    uart: usize,
-    memory: [(usize, usize); 8],
-    memory_count: usize,
+/// A hart listed under `/cpus`.
+struct Cpu {
+    id: usize,
+    /// whether its ISA lists the Sstc extension
+    sstc: bool,
 }
 
-impl BoardInfo {
-    fn parse(dtb_pa: usize) -> Self {
-        use dtb_walker::{Dtb, DtbObj, HeaderError as E, Property, WalkOperation::*};
+fn cpus(tree: &dt::DeviceTree) -> Vec<Cpu> {
+    let Some(node) = tree.find_node("/cpus") else {
+        return Vec::new();
+    };
+    node.children()
+        .filter(|cpu| {
+            cpu.property("device_type").and_then(dt::Property::as_str) == Some("cpu")
+                && cpu.is_enabled()
+        })
+        .filter_map(|cpu| {
+            let &(id, _) = cpu.reg().first()?;
+            let sstc = cpu
+                .property("riscv,isa")
+                .and_then(dt::Property::as_str)
+                .is_some_and(|isa| isa.split('_').any(|ext| ext == "sstc"))
+                || cpu
+                    .property("riscv,isa-extensions")
+                    .is_some_and(|exts| exts.as_str_list().any(|ext| ext == "sstc"));
+            Some(Cpu { id, sstc })
+        })
+        .collect()
+}
 
-        let mut ans = Self {
-            smp: 0,
-            frequency: 0,
-            uart: 0,
-            memory: [(0, 0); 8],
-            memory_count: 0,
-        };
-        unsafe {
-            Dtb::from_raw_parts_filtered(dtb_pa as _, |e| {
-                matches!(e, E::Misaligned(4) | E::LastCompVersion(_))
-            })
-        }
-        .expect("failed to parse dtb")
-        .walk(|ctx, obj| match obj {
-            DtbObj::SubNode { name } => {
-                if ctx.level() == 0 && (name == b"cpus" || name == b"soc") {
-                    StepInto
-                } else if ctx.last() == b"cpus" && name.starts_with(b"cpu@") {
-                    ans.smp += 1;
-                    StepOver
-                } else if ctx.last() == b"soc"
-                    && (name.starts_with(b"uart") || name.starts_with(b"serial"))
-                {
-                    StepInto
-                } else if name.starts_with(b"memory@") {
-                    StepInto
-                } else {
-                    StepOver
-                }
-            }
-            DtbObj::Property(Property::Reg(mut reg)) => {
-                if ctx.last().starts_with(b"uart") || ctx.last().starts_with(b"serial") {
-                    ans.uart = reg.next().unwrap().start;
-                } else if ctx.last().starts_with(b"memory") {
-                    while let Some(r) = reg.next() {
-                        if ans.memory_count >= ans.memory.len() {
-                            break;
-                        }
-                        info!("memory region: {:#x} - {:#x}", r.start, r.end);
-                        ans.memory[ans.memory_count] = (r.start, r.end);
-                        ans.memory_count += 1;
-                    }
-                }
-                StepOut
-            }
-            DtbObj::Property(Property::General { name, value }) => {
-                if ctx.last() == b"cpus" && name.as_bytes() == b"timebase-frequency" {
-                    ans.frequency = match *value {
-                        [a, b, c, d] => u32::from_be_bytes([a, b, c, d]) as _,
-                        [a, b, c, d, e, f, g, h] => u64::from_be_bytes([a, b, c, d, e, f, g, h]),
-                        _ => unreachable!(),
-                    };
-                }
-                StepOver
-            }
-            DtbObj::Property(_) => StepOver,
-        });
-        ans
-    }
+/// `timebase-frequency`, from `/cpus` or else from the first cpu node.
+fn timebase_frequency(tree: &dt::DeviceTree) -> u64 {
+    let cpus = tree.find_node("/cpus");
+    cpus.into_iter()
+        .chain(cpus.into_iter().flat_map(|cpus| cpus.children()))
+        .find_map(|node| node.property("timebase-frequency"))
+        .and_then(dt::Property::as_u64)
+        .unwrap_or(0)
+}
+
+/// `/memreserve/` entries and `/reserved-memory` regions.
+fn reserved_memory(tree: &dt::DeviceTree) -> Vec<(usize, usize)> {
+    let regions = tree
+        .find_node("/reserved-memory")
+        .into_iter()
+        .flat_map(|node| node.children())
+        .flat_map(|node| node.reg());
+    tree.memreserve().iter().copied().chain(regions).collect()
 }
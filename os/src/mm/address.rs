@@ -0,0 +1,199 @@
+use core::{
+    fmt,
+    marker::PhantomData,
+    ops::{Add, AddAssign, Sub},
+};
+
+use super::{AlignSize, PAGE_OFFSET_BITS, PAGE_SIZE, PHYS_VIRT_OFFSET, PN_BITS};
+
+pub trait AlignCheck {
+    const ALIGN_SIZE: usize;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Unaligned;
+
+impl AlignCheck for Unaligned {
+    const ALIGN_SIZE: usize = 1;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Align4K;
+
+impl AlignCheck for Align4K {
+    const ALIGN_SIZE: usize = PAGE_SIZE;
+}
+
+/// A physical address. `A` records the alignment the address is known to have.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PhysAddr<A: AlignCheck = Unaligned>(usize, PhantomData<A>);
+
+/// A virtual address. `A` records the alignment the address is known to have.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VirtAddr<A: AlignCheck = Unaligned>(usize, PhantomData<A>);
+
+/// Half-open range of physical addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysRange {
+    pub start: PhysAddr,
+    pub end: PhysAddr,
+}
+
+/// Half-open range of virtual addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRange {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+}
+
+macro_rules! impl_address {
+    ($addr:ident, $range:ident, $prefix:literal) => {
+        impl $addr {
+            #[inline]
+            pub const fn new(addr: usize) -> $addr<Unaligned> {
+                $addr(addr, PhantomData)
+            }
+        }
+
+        impl<A: AlignCheck> $addr<A> {
+            #[inline]
+            pub const fn as_usize(&self) -> usize {
+                self.0
+            }
+
+            /// Forget the alignment known about this address.
+            #[inline]
+            pub const fn unaligned(self) -> $addr<Unaligned> {
+                $addr(self.0, PhantomData)
+            }
+
+            #[inline]
+            pub fn is_aligned_to(&self, align_size: AlignSize) -> bool {
+                self.0 & (align_size as usize - 1) == 0
+            }
+
+            #[inline]
+            pub const fn align_down<T: AlignCheck>(self) -> $addr<T> {
+                $addr(self.0 & !(T::ALIGN_SIZE - 1), PhantomData)
+            }
+
+            #[inline]
+            pub const fn align_up<T: AlignCheck>(self) -> $addr<T> {
+                $addr(
+                    (self.0 + T::ALIGN_SIZE - 1) & !(T::ALIGN_SIZE - 1),
+                    PhantomData,
+                )
+            }
+        }
+
+        impl<A: AlignCheck> Add<usize> for $addr<A> {
+            type Output = $addr;
+
+            #[inline]
+            fn add(self, rhs: usize) -> $addr {
+                $addr(self.0 + rhs, PhantomData)
+            }
+        }
+
+        impl AddAssign<usize> for $addr {
+            #[inline]
+            fn add_assign(&mut self, rhs: usize) {
+                self.0 += rhs;
+            }
+        }
+
+        impl<A: AlignCheck> Sub<usize> for $addr<A> {
+            type Output = $addr;
+
+            #[inline]
+            fn sub(self, rhs: usize) -> $addr {
+                $addr(self.0 - rhs, PhantomData)
+            }
+        }
+
+        impl<A: AlignCheck, B: AlignCheck> Sub<$addr<B>> for $addr<A> {
+            type Output = usize;
+
+            #[inline]
+            fn sub(self, rhs: $addr<B>) -> usize {
+                self.0 - rhs.0
+            }
+        }
+
+        impl<A: AlignCheck> fmt::Debug for $addr<A> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, concat!($prefix, "{:#x}"), self.0)
+            }
+        }
+
+        impl $range {
+            #[inline]
+            pub const fn new(start: $addr, end: $addr) -> $range {
+                $range { start, end }
+            }
+        }
+    };
+}
+
+impl_address!(PhysAddr, PhysRange, "PA:");
+impl_address!(VirtAddr, VirtRange, "VA:");
+
+impl PhysAddr {
+    /// Address of the first byte of page `number`.
+    #[inline]
+    pub const fn from_page_number(number: usize) -> PhysAddr<Align4K> {
+        PhysAddr(number << PAGE_OFFSET_BITS, PhantomData)
+    }
+}
+
+impl<A: AlignCheck> PhysAddr<A> {
+    /// Number of the page holding this address.
+    #[inline]
+    pub const fn page_number(&self) -> usize {
+        self.0 >> PAGE_OFFSET_BITS
+    }
+
+    /// Where the kernel reaches this address through the linear map of RAM.
+    #[inline]
+    pub const fn to_virt(&self) -> VirtAddr<A> {
+        VirtAddr(self.0 + PHYS_VIRT_OFFSET, PhantomData)
+    }
+}
+
+impl PhysRange {
+    /// Start of every page overlapping this range.
+    pub fn pages(&self) -> impl Iterator<Item = PhysAddr<Align4K>> {
+        let start = self.start.align_down::<Align4K>().0;
+        (start..self.end.0)
+            .step_by(PAGE_SIZE)
+            .map(|addr| PhysAddr(addr, PhantomData))
+    }
+}
+
+impl VirtAddr {
+    #[inline]
+    pub fn from_ptr<T>(ptr: *const T) -> VirtAddr {
+        VirtAddr(ptr as usize, PhantomData)
+    }
+}
+
+impl<A: AlignCheck> VirtAddr<A> {
+    #[inline]
+    pub const fn as_ptr(&self) -> *mut u8 {
+        self.0 as *mut u8
+    }
+
+    /// Physical address behind an address in the linear map of RAM, which
+    /// includes the kernel image.
+    #[inline]
+    pub fn to_phys(&self) -> PhysAddr<A> {
+        debug_assert!(self.0 >= PHYS_VIRT_OFFSET);
+        PhysAddr(self.0 - PHYS_VIRT_OFFSET, PhantomData)
+    }
+
+    /// Index into the page table at `level` for this address.
+    #[inline]
+    pub const fn vpn(&self, level: usize) -> usize {
+        (self.0 >> (PAGE_OFFSET_BITS + level * PN_BITS)) & ((1 << PN_BITS) - 1)
+    }
+}
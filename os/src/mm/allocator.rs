@@ -1,44 +1,208 @@
+use alloc::vec::Vec;
 use core::{
     alloc::{Layout, LayoutError},
-    marker::PhantomData,
+    mem,
     ptr::NonNull,
 };
 
-use buddy_system_allocator::LockedHeap;
+use buddy_system_allocator::Heap;
+use riscv::interrupt::supervisor as interrupt;
+use spin::Mutex;
 
-use super::{AlignSize, Mode, PageTableSpec};
+use super::{Align4K, AlignSize, PhysAddr, VirtAddr, PAGE_SIZE};
 
-pub static FRAME_ALLOCATOR: FrameAllocator<Mode> = FrameAllocator(LockedHeap::empty(), PhantomData);
+const MAX_ZONES: usize = 8;
 
-pub struct FrameAllocator<M>(LockedHeap<32>, PhantomData<M>);
+pub static FRAME_ALLOCATOR: FrameAllocator = FrameAllocator(Mutex::new(Zones {
+    zones: [const { Zone::empty() }; MAX_ZONES],
+    count: 0,
+}));
+
+/// Physical memory allocator keeping one buddy heap per memory region.
+///
+/// Zones are registered with physical addresses, while frames are handed out
+/// as pointers into the high half. Frames may be allocated and freed by
+/// interrupt handlers, so the lock is only taken with interrupts disabled.
+pub struct FrameAllocator(Mutex<Zones>);
+
+struct Zones {
+    zones: [Zone; MAX_ZONES],
+    count: usize,
+}
+
+/// One region of RAM, `[start, end)` in physical addresses.
+struct Zone {
+    start: usize,
+    end: usize,
+    heap: Heap<32>,
+}
+
+impl Zone {
+    const fn empty() -> Zone {
+        Zone {
+            start: 0,
+            end: 0,
+            heap: Heap::empty(),
+        }
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+}
+
+/// Frame usage of one zone, as reported by [`FrameAllocator::stats`].
+#[derive(Debug, Clone, Copy)]
+pub struct ZoneStats {
+    pub start: usize,
+    pub end: usize,
+    pub total_frames: usize,
+    pub free_frames: usize,
+}
+
+impl ZoneStats {
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
     LayoutError(LayoutError),
     OutOfMemory,
+    OutOfZone,
+    TooManyZones,
 }
 
-impl<M> FrameAllocator<M>
-where
-    M: PageTableSpec,
-{
-    pub fn init(&self, start: usize, size: usize) {
-        let mut heap = self.0.lock();
-        unsafe { heap.init(start, size) }
+impl FrameAllocator {
+    fn with_zones<R>(&self, f: impl FnOnce(&mut Zones) -> R) -> R {
+        interrupt::free(|| f(&mut self.0.lock()))
+    }
+
+    /// Register the RAM region `[start, end)` as an empty zone. Memory is
+    /// handed to it with [`FrameAllocator::add_free`].
+    pub fn add_zone(&self, start: usize, end: usize) -> Result<(), Error> {
+        self.with_zones(|zones| {
+            let count = zones.count;
+            let zone = zones.zones.get_mut(count).ok_or(Error::TooManyZones)?;
+            zone.start = start;
+            zone.end = end;
+            zones.count += 1;
+            Ok(())
+        })
+    }
+
+    /// Make `[start, end)` available for allocation, leaving out every range
+    /// in `reserved`. All addresses are physical, and the memory must already
+    /// be reachable through the linear map.
+    pub fn add_free(
+        &self,
+        start: usize,
+        end: usize,
+        reserved: &[(usize, usize)],
+    ) -> Result<(), Error> {
+        if start >= end {
+            return Ok(());
+        }
+        self.with_zones(|zones| {
+            let count = zones.count;
+            let zone = zones.zones[..count]
+                .iter_mut()
+                .find(|zone| zone.contains(start) && end <= zone.end)
+                .ok_or(Error::OutOfZone)?;
+
+            let mut cursor = start;
+            while cursor < end {
+                let next_reserved = reserved
+                    .iter()
+                    .filter(|&&(r_start, r_end)| r_end > cursor && r_start < end)
+                    .min_by_key(|&&(r_start, _)| r_start);
+                let free_end = match next_reserved {
+                    Some(&(r_start, _)) => r_start.max(cursor),
+                    None => end,
+                };
+                let free_start = PhysAddr::new(cursor).align_up::<Align4K>();
+                let free_end = PhysAddr::new(free_end).align_down::<Align4K>();
+                if free_start < free_end {
+                    unsafe {
+                        zone.heap.add_to_heap(
+                            free_start.to_virt().as_usize(),
+                            free_end.to_virt().as_usize(),
+                        )
+                    };
+                }
+                cursor = match next_reserved {
+                    Some(&(_, r_end)) => r_end,
+                    None => end,
+                };
+            }
+            Ok(())
+        })
+    }
+
+    /// Allocate `count` physically contiguous frames, aligned to `1 << order`
+    /// frames.
+    pub fn alloc_pages(&self, count: usize, order: usize) -> Result<Frame, Error> {
+        let layout = Layout::from_size_align(count * PAGE_SIZE, PAGE_SIZE << order)
+            .map_err(Error::LayoutError)?;
+        let ptr = self
+            .with_zones(|zones| {
+                let registered = zones.count;
+                zones.zones[..registered]
+                    .iter_mut()
+                    .find_map(|zone| zone.heap.alloc(layout).ok())
+            })
+            .ok_or(Error::OutOfMemory)?;
+        Ok(Frame {
+            ppn: VirtAddr::from_ptr(ptr.as_ptr()).to_phys().page_number(),
+            count,
+            order,
+        })
     }
 
-    pub fn alloc(&self, size: usize) -> Result<Frame, Error> {
-        let align = Self::fit_align_from_size(size);
-        let layout = Layout::from_size_align(size, align).map_err(Error::LayoutError)?;
-        let mut heap = self.0.lock();
-        heap.alloc(layout)
-            .map(|ptr| Frame { ptr, layout })
-            .map_err(|_| Error::OutOfMemory)
+    /// Allocate `count` physically contiguous frames, aligned so that large
+    /// runs can be mapped with huge pages.
+    pub fn alloc_contiguous(&self, count: usize) -> Result<Frame, Error> {
+        self.alloc_pages(count, Self::contiguous_order(count))
+    }
+
+    /// Alignment order of `count` frames from
+    /// [`FrameAllocator::alloc_contiguous`], to take them back with
+    /// [`Frame::from_raw`] once leaked.
+    pub fn contiguous_order(count: usize) -> usize {
+        (Self::fit_align_from_size(count * PAGE_SIZE) / PAGE_SIZE).trailing_zeros() as usize
     }
 
     fn dealloc(&self, frame: &Frame) {
-        let mut heap = self.0.lock();
-        heap.dealloc(frame.ptr, frame.layout);
+        let addr = frame.phys_addr().as_usize();
+        let ptr = unsafe { NonNull::new_unchecked(frame.virt_addr().as_ptr()) };
+        self.with_zones(|zones| {
+            let count = zones.count;
+            let zone = zones.zones[..count]
+                .iter_mut()
+                .find(|zone| zone.contains(addr))
+                .expect("frame outside of every zone");
+            zone.heap.dealloc(ptr, frame.layout());
+        });
+    }
+
+    /// Frame usage of every registered zone.
+    pub fn stats(&self) -> Vec<ZoneStats> {
+        self.with_zones(|zones| {
+            zones.zones[..zones.count]
+                .iter()
+                .map(|zone| {
+                    let total = zone.heap.stats_total_bytes() / PAGE_SIZE;
+                    let used = zone.heap.stats_alloc_actual() / PAGE_SIZE;
+                    ZoneStats {
+                        start: zone.start,
+                        end: zone.end,
+                        total_frames: total,
+                        free_frames: total - used,
+                    }
+                })
+                .collect()
+        })
     }
 
     fn fit_align_from_size(size: usize) -> usize {
@@ -52,9 +216,52 @@ where
     }
 }
 
+/// A run of physically contiguous frames, returned to the allocator on drop.
 pub struct Frame {
-    pub ptr: NonNull<u8>,
-    pub layout: Layout,
+    ppn: usize,
+    count: usize,
+    order: usize,
+}
+
+impl Frame {
+    /// Take back frames given up with [`Frame::leak`].
+    ///
+    /// # Safety
+    ///
+    /// `ppn`, `count` and `order` must be those of a leaked frame, and nothing
+    /// may use its memory afterwards.
+    pub unsafe fn from_raw(ppn: usize, count: usize, order: usize) -> Frame {
+        Frame { ppn, count, order }
+    }
+
+    /// Give up ownership without freeing, returning the first page number.
+    pub fn leak(self) -> usize {
+        let ppn = self.ppn;
+        mem::forget(self);
+        ppn
+    }
+
+    #[inline]
+    pub fn pages(&self) -> usize {
+        self.count
+    }
+
+    #[inline]
+    pub fn phys_addr(&self) -> PhysAddr<Align4K> {
+        PhysAddr::from_page_number(self.ppn)
+    }
+
+    /// Where the kernel reaches the frames, through the linear map.
+    #[inline]
+    pub fn virt_addr(&self) -> VirtAddr<Align4K> {
+        self.phys_addr().to_virt()
+    }
+
+    fn layout(&self) -> Layout {
+        unsafe {
+            Layout::from_size_align_unchecked(self.count * PAGE_SIZE, PAGE_SIZE << self.order)
+        }
+    }
 }
 
 impl Drop for Frame {
@@ -1,14 +1,33 @@
+mod address;
 pub mod allocator;
 
+pub use address::{Align4K, AlignCheck, PhysAddr, PhysRange, VirtAddr, VirtRange};
+
+use alloc::vec::Vec;
 use core::{
+    arch::asm,
     cell::UnsafeCell,
     marker::PhantomData,
-    ptr::{write_volatile, NonNull},
+    ptr::write_volatile,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use bitflags::bitflags;
 
-const PAGE_SIZE: usize = 4096;
+use allocator::{Frame, FRAME_ALLOCATOR};
+
+use crate::ipi::{self, HartSet};
+
+crate::percpu! {
+    /// `satp` of the root table last activated on this hart.
+    static ACTIVE_SATP: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Kernel virtual address of physical address 0. All RAM, the kernel image
+/// included, is mapped linearly from here.
+pub const PHYS_VIRT_OFFSET: usize = 0xffff_ffc0_0000_0000;
+
+pub const PAGE_SIZE: usize = 4096;
 const PN_BITS: usize = 9;
 const PAGE_OFFSET_BITS: usize = 12;
 const RSW_BITS: usize = 2;
@@ -100,6 +119,7 @@ mod satp_mask {
 }
 
 bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct PageTableEntryFlags: usize {
         const V = 0b00000001;
         const R = 0b00000010;
@@ -121,7 +141,27 @@ pub const KERNEL_PTE_FLAGS: PageTableEntryFlags = PageTableEntryFlags::from_bits
         | PageTableEntryFlags::D.bits(),
 );
 
+pub const KERNEL_TEXT_FLAGS: PageTableEntryFlags = PageTableEntryFlags::from_bits_truncate(
+    PageTableEntryFlags::V.bits()
+        | PageTableEntryFlags::R.bits()
+        | PageTableEntryFlags::X.bits()
+        | PageTableEntryFlags::A.bits(),
+);
+
+pub const KERNEL_RODATA_FLAGS: PageTableEntryFlags = PageTableEntryFlags::from_bits_truncate(
+    PageTableEntryFlags::V.bits() | PageTableEntryFlags::R.bits() | PageTableEntryFlags::A.bits(),
+);
+
+pub const KERNEL_DATA_FLAGS: PageTableEntryFlags = PageTableEntryFlags::from_bits_truncate(
+    PageTableEntryFlags::V.bits()
+        | PageTableEntryFlags::R.bits()
+        | PageTableEntryFlags::W.bits()
+        | PageTableEntryFlags::A.bits()
+        | PageTableEntryFlags::D.bits(),
+);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[allow(clippy::enum_clike_unportable_variant)] // the kernel only targets riscv64
 pub enum AlignSize {
     Page4K = 8 << 9,
     Page2M = 8 << (9 * 2),
@@ -129,6 +169,19 @@ pub enum AlignSize {
     Page512G = 8 << (9 * 4),
 }
 
+impl AlignSize {
+    /// Page table level holding a leaf of this size, counting from 0 for 4 KiB pages.
+    #[inline]
+    pub const fn level(self) -> usize {
+        match self {
+            AlignSize::Page4K => 0,
+            AlignSize::Page2M => 1,
+            AlignSize::Page1G => 2,
+            AlignSize::Page512G => 3,
+        }
+    }
+}
+
 #[repr(C, align(4096))]
 pub struct PageTable([PageTableEntry; 512]);
 
@@ -137,20 +190,133 @@ impl PageTable {
         PageTable([PageTableEntry::zero(); 512])
     }
 
+    /// Physical page number of this table.
+    ///
+    /// Statically allocated tables are reached by their physical address before
+    /// the MMU is on, and through the high half afterwards.
     #[inline]
     pub fn ppn(&self) -> usize {
-        core::ptr::addr_of!(*self) as *const _ as usize >> 12
+        let addr = core::ptr::addr_of!(*self) as usize;
+        addr.checked_sub(PHYS_VIRT_OFFSET).unwrap_or(addr) >> 12
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|pte| !pte.is_valid())
+    }
+}
+
+/// Access the page table stored in physical page `ppn`.
+///
+/// Page tables are allocated from RAM, which the kernel reaches through the
+/// linear map once it runs in the high half.
+#[inline]
+unsafe fn table_at<'a>(ppn: usize) -> &'a mut PageTable {
+    &mut *PhysAddr::new(ppn << PAGE_OFFSET_BITS)
+        .to_virt()
+        .as_ptr()
+        .cast::<PageTable>()
+}
+
+fn alloc_table() -> Result<usize, Error> {
+    let frame = FRAME_ALLOCATOR
+        .alloc_pages(1, 0)
+        .map_err(|_| Error::OutOfMemory)?;
+    let ppn = frame.leak();
+    unsafe { *table_at(ppn) = PageTable::zero() };
+    Ok(ppn)
+}
+
+unsafe fn free_table(ppn: usize) {
+    drop(Frame::from_raw(ppn, 1, 0));
+}
+
+/// Turn the huge leaf `pte` at `level` into a table of next-level leaves
+/// mapping the same memory with the same flags.
+unsafe fn split(pte: &mut PageTableEntry, level: usize) -> Result<(), Error> {
+    let ppn = alloc_table()?;
+    let table = table_at(ppn);
+    let step = 1 << ((level - 1) * PN_BITS);
+    for (i, entry) in table.0.iter_mut().enumerate() {
+        *entry = PageTableEntry::new(pte.full_ppn() + i * step, pte.flags());
+    }
+    write_volatile(pte, PageTableEntry::new(ppn, PageTableEntryFlags::V));
+    Ok(())
+}
+
+/// Call `f` with every leaf covering `[start, end)` below the `level` table,
+/// together with the virtual address the leaf starts at.
+///
+/// Huge leaves only partly inside the range are split, and tables left empty
+/// are freed. Returns whether any table was freed.
+unsafe fn walk_leaves(
+    table: &mut PageTable,
+    level: usize,
+    start: usize,
+    end: usize,
+    f: &mut dyn FnMut(&mut PageTableEntry, usize),
+) -> Result<bool, Error> {
+    let entry_size = PAGE_SIZE << (level * PN_BITS);
+    let mut freed = false;
+    let mut va = start;
+    while va < end {
+        let entry_start = va & !(entry_size - 1);
+        let next = entry_start
+            .checked_add(entry_size)
+            .map_or(end, |next| next.min(end));
+        let pte = &mut table.0[VirtAddr::new(va).vpn(level)];
+        if pte.is_valid() {
+            let covered = va == entry_start && next - entry_start == entry_size;
+            if level == 0 || (pte.is_leaf() && covered) {
+                f(pte, entry_start);
+            } else {
+                if pte.is_leaf() {
+                    split(pte, level)?;
+                }
+                let ppn = pte.full_ppn();
+                freed |= walk_leaves(table_at(ppn), level - 1, va, next, f)?;
+                if table_at(ppn).is_empty() {
+                    write_volatile(pte, PageTableEntry::zero());
+                    free_table(ppn);
+                    freed = true;
+                }
+            }
+        }
+        va = next;
+    }
+    Ok(freed)
+}
+
+/// Pages past which a local flush drops the whole address space rather than
+/// fencing page by page.
+const FLUSH_ALL_PAGES: usize = 64;
+
+/// The leaves a walk changed, to flush once it is done.
+#[derive(Default)]
+struct Touched {
+    /// where the first leaf starts, the walk going up from there
+    start: Option<usize>,
+    /// whether any leaf was global
+    global: bool,
+}
+
+impl Touched {
+    fn add(&mut self, pte: &PageTableEntry, va: usize) {
+        self.start.get_or_insert(va);
+        self.global |= pte.flags().contains(PageTableEntryFlags::G);
     }
 }
 
 #[repr(C, align(4096))]
 pub struct RootPageTable<S: PageTableSpec>(UnsafeCell<PageTable>, core::marker::PhantomData<S>);
 
-unsafe impl Sync for RootPageTable<Sv39> {}
+unsafe impl<S: PageTableSpec> Sync for RootPageTable<S> {}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Error {
     AddressNotAligned,
+    AlreadyMapped,
+    InvalidFlags,
     OutOfMemory,
 }
 
@@ -159,6 +325,13 @@ impl<S: PageTableSpec> RootPageTable<S> {
         RootPageTable(UnsafeCell::new(PageTable::zero()), PhantomData)
     }
 
+    /// Allocate an empty root table from [`FRAME_ALLOCATOR`]. The table is
+    /// never freed.
+    pub fn alloc() -> Result<&'static RootPageTable<S>, Error> {
+        let ppn = alloc_table()?;
+        Ok(unsafe { &*(table_at(ppn) as *const PageTable as *const RootPageTable<S>) })
+    }
+
     #[inline]
     fn ppn(&self) -> usize {
         unsafe { (*self.0.get()).ppn() }
@@ -174,79 +347,358 @@ impl<S: PageTableSpec> RootPageTable<S> {
 
     #[inline]
     pub fn active(&self, asid: usize) {
-        riscv::register::satp::write(self.satp(asid));
+        let satp = self.satp(asid);
+        riscv::register::satp::write(satp);
         riscv::asm::sfence_vma_all();
+        // the boot page table is activated before there is a per-hart area
+        if crate::percpu::ready() {
+            ACTIVE_SATP.with(|active| active.store(satp, Ordering::Release));
+        }
     }
 
     #[inline]
-    pub unsafe fn map(
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn root(&self) -> &mut PageTable {
+        &mut *self.0.get()
+    }
+
+    /// Map one page of `align_size` at `virt_addr` to `phy_addr`.
+    ///
+    /// Intermediate tables are allocated from [`FRAME_ALLOCATOR`] on demand, and
+    /// must be reachable through the high-half mapping of RAM, so anything
+    /// smaller than the root's leaf size can only be mapped after the kernel
+    /// has jumped to the high half.
+    pub unsafe fn map<A: AlignCheck, B: AlignCheck>(
         &self,
-        virt_addr: Address,
-        phy_addr: Address,
+        virt_addr: VirtAddr<A>,
+        phy_addr: PhysAddr<B>,
         align_size: AlignSize,
         flags: PageTableEntryFlags,
     ) -> Result<(), Error> {
-        if S::LEVEL < 4 && align_size >= AlignSize::Page512G {
+        let leaf_level = align_size.level();
+        if leaf_level >= S::LEVEL {
             return Err(Error::OutOfMemory);
         }
 
-        if S::LEVEL < 3 && align_size >= AlignSize::Page1G {
-            return Err(Error::OutOfMemory);
+        if !virt_addr.is_aligned_to(align_size) || !phy_addr.is_aligned_to(align_size) {
+            return Err(Error::AddressNotAligned);
         }
 
-        if S::LEVEL < 2 && align_size >= AlignSize::Page2M {
-            return Err(Error::OutOfMemory);
+        let mut table = self.root();
+        for level in (leaf_level + 1..S::LEVEL).rev() {
+            let pte = &mut table.0[virt_addr.vpn(level)];
+            if !pte.is_valid() {
+                let ppn = alloc_table()?;
+                write_volatile(pte, PageTableEntry::new(ppn, PageTableEntryFlags::V));
+            } else if pte.is_leaf() {
+                return Err(Error::AlreadyMapped);
+            }
+            table = table_at(pte.full_ppn());
         }
 
-        if align_size == AlignSize::Page1G && S::LEVEL == 3 {
-            if !virt_addr.is_aligned::<Align1G>() || !phy_addr.is_aligned::<Align1G>() {
-                return Err(Error::AddressNotAligned);
-            }
-            let vpn_2 = virt_addr.pn::<2>();
-            let mut pte = PageTableEntry::zero();
-            pte.set_ppn_2(phy_addr.pn::<2>());
-            pte.set_flags(flags);
+        let pte = &mut table.0[virt_addr.vpn(leaf_level)];
+        if pte.is_valid() {
+            return Err(Error::AlreadyMapped);
+        }
+        write_volatile(pte, PageTableEntry::new(phy_addr.page_number(), flags));
+        Ok(())
+    }
+
+    /// Map `range` to physical memory starting at `phy_addr`, using the largest
+    /// page size that alignment and the remaining length allow.
+    pub unsafe fn map_range(
+        &self,
+        range: VirtRange,
+        phy_addr: PhysAddr,
+        flags: PageTableEntryFlags,
+    ) -> Result<(), Error> {
+        const SIZES: [AlignSize; 4] = [
+            AlignSize::Page512G,
+            AlignSize::Page1G,
+            AlignSize::Page2M,
+            AlignSize::Page4K,
+        ];
+        let mut va = range.start;
+        let mut pa = phy_addr;
+        while va < range.end {
+            let align_size = SIZES
+                .into_iter()
+                .find(|&size| {
+                    size as usize <= S::MAX_PAGE_SIZE
+                        && va.is_aligned_to(size)
+                        && pa.is_aligned_to(size)
+                        && range.end - va >= size as usize
+                })
+                .ok_or(Error::AddressNotAligned)?;
+            self.map(va, pa, align_size, flags)?;
+            va += align_size as usize;
+            pa += align_size as usize;
+        }
+        Ok(())
+    }
+
+    /// Remove every mapping in `range`.
+    ///
+    /// Huge pages sticking out of `range` are split so the part outside stays
+    /// mapped, and intermediate tables left empty are returned to
+    /// [`FRAME_ALLOCATOR`].
+    pub unsafe fn unmap(&self, range: VirtRange) -> Result<(), Error> {
+        let (start, end) = Self::page_range(&range)?;
+        let mut touched = Touched::default();
+        let freed = walk_leaves(self.root(), S::LEVEL - 1, start, end, &mut |pte, va| {
+            touched.add(pte, va);
+            write_volatile(pte, PageTableEntry::zero());
+        })?;
+        if freed {
+            self.flush_asid(touched.global);
+        } else {
+            self.flush(touched, end);
+        }
+        Ok(())
+    }
 
-            write_volatile(&mut (*self.0.get()).0[vpn_2], pte);
-            return Ok(());
+    /// Replace the permissions (R, W, X and U) of every mapping in `range`,
+    /// leaving holes untouched. V, G, A and D are kept from each entry.
+    ///
+    /// Huge pages sticking out of `range` are split first.
+    pub unsafe fn protect(
+        &self,
+        range: VirtRange,
+        flags: PageTableEntryFlags,
+    ) -> Result<(), Error> {
+        let permissions = PageTableEntryFlags::R
+            | PageTableEntryFlags::W
+            | PageTableEntryFlags::X
+            | PageTableEntryFlags::U;
+        let flags = flags & permissions;
+        // W without R is reserved
+        if !flags
+            .intersects(PageTableEntryFlags::R | PageTableEntryFlags::W | PageTableEntryFlags::X)
+            || (flags.contains(PageTableEntryFlags::W) && !flags.contains(PageTableEntryFlags::R))
+        {
+            return Err(Error::InvalidFlags);
         }
+        let (start, end) = Self::page_range(&range)?;
+        let mut touched = Touched::default();
+        walk_leaves(self.root(), S::LEVEL - 1, start, end, &mut |pte, va| {
+            touched.add(pte, va);
+            pte.set_flags((pte.flags() - permissions) | flags);
+        })?;
+        self.flush(touched, end);
+        Ok(())
+    }
+
+    /// Point `range` at physical memory starting at `phy_addr`, dropping
+    /// whatever was mapped there before.
+    pub unsafe fn remap(
+        &self,
+        range: VirtRange,
+        phy_addr: PhysAddr,
+        flags: PageTableEntryFlags,
+    ) -> Result<(), Error> {
+        self.unmap(range)?;
+        self.map_range(range, phy_addr, flags)
+    }
 
-        todo!()
+    fn page_range(range: &VirtRange) -> Result<(usize, usize), Error> {
+        if !range.start.is_aligned_to(AlignSize::Page4K)
+            || !range.end.is_aligned_to(AlignSize::Page4K)
+        {
+            return Err(Error::AddressNotAligned);
+        }
+        Ok((range.start.as_usize(), range.end.as_usize()))
     }
 
-    pub fn translate<T>(&self, virt_addr: Address) -> Option<Address> {
-        let mut ppn = self.ppn();
-        let mut addr = virt_addr.as_usize();
-        for i in (0..S::LEVEL).rev() {
-            let vpn = match i {
-                0 => virt_addr.pn::<0>(),
-                1 => virt_addr.pn::<1>(),
-                2 => virt_addr.pn::<2>(),
-                3 => virt_addr.pn::<3>(),
-                4 => virt_addr.pn::<4>(),
-                _ => unreachable!(),
+    /// ASID this table is live under, if it is the one installed in `satp`.
+    fn live_asid(&self) -> Option<usize> {
+        let satp = riscv::register::satp::read();
+        (satp.ppn() == self.ppn()).then(|| satp.asid())
+    }
+
+    /// Other harts this table is live on, grouped by the ASID each of them
+    /// activated it under.
+    fn remote_harts(&self) -> Vec<(usize, HartSet)> {
+        let mut groups: Vec<(usize, HartSet)> = Vec::new();
+        if !crate::percpu::ready() {
+            return groups;
+        }
+        for hartid in ipi::iter(ipi::others()) {
+            let Some(active) = ACTIVE_SATP.remote_ptr(hartid) else {
+                continue;
             };
-            let pte = unsafe { &(*self.0.get()).0[vpn] };
+            let satp = unsafe { &*active }.load(Ordering::Acquire);
+            if satp & satp_mask::PPN_MASK != self.ppn() {
+                continue;
+            }
+            let asid = (satp & satp_mask::ASID_MASK) >> 44;
+            match groups.iter_mut().find(|(id, _)| *id == asid) {
+                Some((_, harts)) => *harts |= 1 << hartid,
+                None => groups.push((asid, 1 << hartid)),
+            }
+        }
+        groups
+    }
+
+    /// Fence `[start, start + size)` on the other harts the table is live on:
+    /// one shootdown for every address space if `global`, else one per ASID.
+    fn shootdown(&self, start: usize, size: usize, global: bool) {
+        let groups = self.remote_harts();
+        if global {
+            let harts = groups.iter().fold(0, |all, &(_, harts)| all | harts);
+            ipi::shootdown(harts, start, size, None);
+        } else {
+            for (asid, harts) in groups {
+                ipi::shootdown(harts, start, size, Some(asid));
+            }
+        }
+    }
+
+    /// Drop the cached translations of the leaves a walk ending at `end`
+    /// changed, on every hart the table is live on: fences here, one per page
+    /// or one for everything past [`FLUSH_ALL_PAGES`], and a single shootdown
+    /// for the other harts.
+    ///
+    /// Tables that are not live need nothing: `active` flushes the whole TLB
+    /// when switching to them.
+    fn flush(&self, touched: Touched, end: usize) {
+        let Some(start) = touched.start else {
+            return;
+        };
+        if let Some(asid) = self.live_asid() {
+            unsafe {
+                if (end - start) / PAGE_SIZE > FLUSH_ALL_PAGES {
+                    if touched.global {
+                        riscv::asm::sfence_vma_all();
+                    } else {
+                        asm!("sfence.vma zero, {}", in(reg) asid);
+                    }
+                } else {
+                    for va in (start..end).step_by(PAGE_SIZE) {
+                        if touched.global {
+                            asm!("sfence.vma {}, zero", in(reg) va);
+                        } else {
+                            riscv::asm::sfence_vma(asid, va);
+                        }
+                    }
+                }
+            }
+        }
+        self.shootdown(start, end - start, touched.global);
+    }
+
+    /// Drop every cached translation of this address space, including the
+    /// non-leaf entries that a per-address fence may keep, on every hart the
+    /// table is live on. Global leaves are cached outside any ASID, so when
+    /// one went away the whole TLB goes.
+    fn flush_asid(&self, global: bool) {
+        if let Some(asid) = self.live_asid() {
+            if global {
+                riscv::asm::sfence_vma_all();
+            } else {
+                unsafe { asm!("sfence.vma zero, {}", in(reg) asid) };
+            }
+        }
+        self.shootdown(0, usize::MAX, global);
+    }
+
+    pub fn translate(&self, virt_addr: VirtAddr) -> Option<PhysAddr> {
+        let mut table: &PageTable = unsafe { self.root() };
+        for level in (0..S::LEVEL).rev() {
+            let pte = table.0[virt_addr.vpn(level)];
             if !pte.is_valid() {
                 return None;
             }
-            ppn = match i {
-                0 => pte.ppn_0(),
-                1 => pte.ppn_1(),
-                2 => pte.ppn_2(),
-                3 => pte.ppn_3(),
-                4 => pte.ppn_4(),
-                _ => unreachable!(),
-            };
-            addr = (ppn << 12) | (addr & 0xfff);
+            if pte.is_leaf() {
+                let offset = virt_addr.as_usize() & ((PAGE_SIZE << (level * PN_BITS)) - 1);
+                return Some(PhysAddr::from_page_number(pte.full_ppn()) + offset);
+            }
+            table = unsafe { table_at(pte.full_ppn()) };
         }
-        Some(Address::new(addr))
+        None
+    }
+
+    /// The entries the MMU goes through to translate `virt_addr`, from the root
+    /// down, as `(level, entry)`. The walk stops at a leaf or an invalid entry.
+    pub fn walk(&self, virt_addr: VirtAddr) -> Vec<(usize, PageTableEntry)> {
+        let mut entries = Vec::new();
+        let mut table: &PageTable = unsafe { self.root() };
+        for level in (0..S::LEVEL).rev() {
+            let pte = table.0[virt_addr.vpn(level)];
+            entries.push((level, pte));
+            if !pte.is_valid() || pte.is_leaf() {
+                break;
+            }
+            table = unsafe { table_at(pte.full_ppn()) };
+        }
+        entries
+    }
+}
+
+/// Operations on a root page table whose paging mode is only known at run time.
+pub trait AddressSpace: Sync {
+    fn active(&self, asid: usize);
+    fn translate(&self, virt_addr: VirtAddr) -> Option<PhysAddr>;
+    fn walk(&self, virt_addr: VirtAddr) -> Vec<(usize, PageTableEntry)>;
+    unsafe fn map_range(
+        &self,
+        range: VirtRange,
+        phy_addr: PhysAddr,
+        flags: PageTableEntryFlags,
+    ) -> Result<(), Error>;
+    unsafe fn unmap(&self, range: VirtRange) -> Result<(), Error>;
+    unsafe fn protect(&self, range: VirtRange, flags: PageTableEntryFlags) -> Result<(), Error>;
+    unsafe fn remap(
+        &self,
+        range: VirtRange,
+        phy_addr: PhysAddr,
+        flags: PageTableEntryFlags,
+    ) -> Result<(), Error>;
+}
+
+impl<S: PageTableSpec> AddressSpace for RootPageTable<S> {
+    fn active(&self, asid: usize) {
+        self.active(asid)
+    }
+
+    fn translate(&self, virt_addr: VirtAddr) -> Option<PhysAddr> {
+        self.translate(virt_addr)
+    }
+
+    fn walk(&self, virt_addr: VirtAddr) -> Vec<(usize, PageTableEntry)> {
+        self.walk(virt_addr)
+    }
+
+    unsafe fn map_range(
+        &self,
+        range: VirtRange,
+        phy_addr: PhysAddr,
+        flags: PageTableEntryFlags,
+    ) -> Result<(), Error> {
+        self.map_range(range, phy_addr, flags)
+    }
+
+    unsafe fn unmap(&self, range: VirtRange) -> Result<(), Error> {
+        self.unmap(range)
+    }
+
+    unsafe fn protect(&self, range: VirtRange, flags: PageTableEntryFlags) -> Result<(), Error> {
+        self.protect(range, flags)
+    }
+
+    unsafe fn remap(
+        &self,
+        range: VirtRange,
+        phy_addr: PhysAddr,
+        flags: PageTableEntryFlags,
+    ) -> Result<(), Error> {
+        self.remap(range, phy_addr, flags)
     }
 }
 
 #[derive(Clone, Copy)]
 pub struct PageTableEntry(usize);
 
+#[allow(dead_code)]
 impl PageTableEntry {
     pub const fn zero() -> PageTableEntry {
         PageTableEntry(0)
@@ -299,7 +751,7 @@ impl PageTableEntry {
 
     #[inline]
     pub const fn ppn_0(&self) -> usize {
-        self.0 & pte_mask::PPN_0_MASK >> (PTE_FLAGS_BITS + RSW_BITS)
+        (self.0 & pte_mask::PPN_0_MASK) >> (PTE_FLAGS_BITS + RSW_BITS)
     }
 
     #[inline]
@@ -310,7 +762,7 @@ impl PageTableEntry {
 
     #[inline]
     pub const fn ppn_1(&self) -> usize {
-        self.0 & pte_mask::PPN_1_MASK >> (PTE_FLAGS_BITS + RSW_BITS + PN_BITS)
+        (self.0 & pte_mask::PPN_1_MASK) >> (PTE_FLAGS_BITS + RSW_BITS + PN_BITS)
     }
 
     #[inline]
@@ -321,7 +773,7 @@ impl PageTableEntry {
 
     #[inline]
     pub const fn ppn_2(&self) -> usize {
-        self.0 & pte_mask::PPN_2_MASK >> (PTE_FLAGS_BITS + RSW_BITS + PN_BITS * 2)
+        (self.0 & pte_mask::PPN_2_MASK) >> (PTE_FLAGS_BITS + RSW_BITS + PN_BITS * 2)
     }
 
     #[inline]
@@ -332,7 +784,7 @@ impl PageTableEntry {
 
     #[inline]
     pub const fn ppn_3(&self) -> usize {
-        self.0 & pte_mask::PPN_3_MASK >> (PTE_FLAGS_BITS + RSW_BITS + PN_BITS * 3)
+        (self.0 & pte_mask::PPN_3_MASK) >> (PTE_FLAGS_BITS + RSW_BITS + PN_BITS * 3)
     }
 
     #[inline]
@@ -343,7 +795,7 @@ impl PageTableEntry {
 
     #[inline]
     pub const fn ppn_4(&self) -> usize {
-        self.0 & pte_mask::PPN_4_MASK >> (PTE_FLAGS_BITS + RSW_BITS + PN_BITS * 4)
+        (self.0 & pte_mask::PPN_4_MASK) >> (PTE_FLAGS_BITS + RSW_BITS + PN_BITS * 4)
     }
 
     #[inline]
@@ -352,6 +804,13 @@ impl PageTableEntry {
             | (ppn << (PTE_FLAGS_BITS + RSW_BITS + PN_BITS * 4) & pte_mask::PPN_4_MASK);
     }
 
+    /// The whole physical page number, regardless of the level of the entry.
+    #[inline]
+    pub const fn full_ppn(&self) -> usize {
+        (self.0 & !(pte_mask::FLAGS_MASK | pte_mask::RSW_MASK | pte_mask::UNUSED_MASK))
+            >> (PTE_FLAGS_BITS + RSW_BITS)
+    }
+
     #[inline]
     pub const fn is_valid(&self) -> bool {
         self.flags().contains(PageTableEntryFlags::V)
@@ -359,112 +818,85 @@ impl PageTableEntry {
 
     #[inline]
     pub const fn is_leaf(&self) -> bool {
-        const NO_LEAF: usize = PageTableEntryFlags::R.bits()
+        const LEAF: usize = PageTableEntryFlags::R.bits()
             | PageTableEntryFlags::W.bits()
             | PageTableEntryFlags::X.bits();
-        !self
-            .flags()
-            .contains(PageTableEntryFlags::from_bits_truncate(NO_LEAF))
+        self.flags()
+            .intersects(PageTableEntryFlags::from_bits_truncate(LEAF))
     }
 }
 
-pub trait AlignCheck {
-    const ALIGN_SIZE: usize;
+pub trait PageTableSpec {
+    const MODE: usize;
+    const LEVEL: usize;
+    const MAX_PAGE_SIZE: usize = PAGE_SIZE << (PN_BITS * (Self::LEVEL - 1));
 }
 
-pub struct Unaligned;
+pub struct Sv39;
 
-impl AlignCheck for Unaligned {
-    const ALIGN_SIZE: usize = 0;
+impl PageTableSpec for Sv39 {
+    const MODE: usize = PagingMode::Sv39 as usize;
+    const LEVEL: usize = 3;
 }
 
-pub struct Align4K;
+pub struct Sv48;
 
-impl AlignCheck for Align4K {
-    const ALIGN_SIZE: usize = PAGE_SIZE;
+impl PageTableSpec for Sv48 {
+    const MODE: usize = PagingMode::Sv48 as usize;
+    const LEVEL: usize = 4;
 }
 
-pub struct Align2M;
-impl AlignCheck for Align2M {
-    const ALIGN_SIZE: usize = PAGE_SIZE << PN_BITS;
-}
-
-pub struct Align1G;
-impl AlignCheck for Align1G {
-    const ALIGN_SIZE: usize = PAGE_SIZE << (PN_BITS * 2);
-}
+pub struct Sv57;
 
-pub struct Align512G;
-impl AlignCheck for Align512G {
-    const ALIGN_SIZE: usize = PAGE_SIZE << (PN_BITS * 3);
+impl PageTableSpec for Sv57 {
+    const MODE: usize = PagingMode::Sv57 as usize;
+    const LEVEL: usize = 5;
 }
 
-pub struct PhysAddr<A: AlignCheck = Unaligned>(usize, core::marker::PhantomData<A>);
-
-pub struct VirtAddr<A: AlignCheck = Unaligned>(NonNull<u8>, core::marker::PhantomData<A>);
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub struct Address<A: AlignCheck = Unaligned>(usize, core::marker::PhantomData<A>);
-
-impl Address {
-    #[inline]
-    pub const fn new(addr: usize) -> Address<Unaligned> {
-        Address(addr, PhantomData)
-    }
+/// Paging modes, numbered as in the `MODE` field of `satp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    Sv39 = 8,
+    Sv48 = 9,
+    Sv57 = 10,
 }
 
-impl<A: AlignCheck> Address<A> {
-    #[inline]
-    pub const fn as_usize(&self) -> usize {
-        self.0
-    }
-
-    #[inline]
-    pub fn offset(&self) -> usize {
-        self.0 & addr_mask::OFFSET_MASK
-    }
-
-    #[inline]
-    pub const fn pn<const N: usize>(&self) -> usize {
-        const {
-            assert!(N < 5);
+impl PagingMode {
+    /// Find the deepest paging mode this hart supports by writing `satp` and
+    /// reading it back, as unsupported modes leave `satp` untouched.
+    ///
+    /// Must run in the high half on an Sv39 table. Each candidate root chains
+    /// its last entry down to the live Sv39 root, so the kernel stays mapped at
+    /// the same addresses whether or not the write sticks.
+    pub fn probe() -> PagingMode {
+        let boot = riscv::register::satp::read();
+        assert_eq!(boot.mode(), riscv::register::satp::Mode::Sv39);
+
+        let high_half = VirtAddr::new(PHYS_VIRT_OFFSET);
+        let (Ok(sv48), Ok(sv57)) = (alloc_table(), alloc_table()) else {
+            return PagingMode::Sv39;
+        };
+        unsafe {
+            table_at(sv48).0[high_half.vpn(3)] =
+                PageTableEntry::new(boot.ppn(), PageTableEntryFlags::V);
+            table_at(sv57).0[high_half.vpn(4)] = PageTableEntry::new(sv48, PageTableEntryFlags::V);
         }
-        match N {
-            0 => (self.0 & addr_mask::PN_0_MASK) >> PAGE_OFFSET_BITS,
-            1 => (self.0 & addr_mask::PN_1_MASK) >> (PAGE_OFFSET_BITS + PN_BITS),
-            2 => (self.0 & addr_mask::PN_2_MASK) >> (PAGE_OFFSET_BITS + 2 * PN_BITS),
-            3 => (self.0 & addr_mask::PN_3_MASK) >> (PAGE_OFFSET_BITS + 3 * PN_BITS),
-            4 => (self.0 & addr_mask::PN_4_MASK) >> (PAGE_OFFSET_BITS + 4 * PN_BITS),
-            _ => unreachable!(),
-        }
-    }
 
-    #[inline]
-    pub fn is_aligned<T: AlignCheck>(&self) -> bool {
-        self.0 & (T::ALIGN_SIZE - 1) == 0
-    }
-
-    #[inline]
-    pub fn check_alignment<T: AlignCheck>(self) -> Result<Address<T>, Error> {
-        if self.is_aligned::<T>() {
-            Ok(Address(self.0, PhantomData))
-        } else {
-            Err(Error::AddressNotAligned)
+        let mode = [(PagingMode::Sv57, sv57), (PagingMode::Sv48, sv48)]
+            .into_iter()
+            .find(|&(mode, ppn)| {
+                riscv::register::satp::write((mode as usize) << 60 | ppn);
+                let supported = riscv::register::satp::read().bits() >> 60 == mode as usize;
+                riscv::register::satp::write(boot.bits());
+                riscv::asm::sfence_vma_all();
+                supported
+            })
+            .map_or(PagingMode::Sv39, |(mode, _)| mode);
+
+        unsafe {
+            free_table(sv57);
+            free_table(sv48);
         }
+        mode
     }
 }
-
-pub trait PageTableSpec {
-    const MODE: usize;
-    const LEVEL: usize;
-    const MAX_PAGE_SIZE: usize = 1 << (9 * Self::LEVEL);
-}
-
-pub struct Sv39;
-
-impl PageTableSpec for Sv39 {
-    const MODE: usize = 8;
-    const LEVEL: usize = 3;
-}
-
-pub type Mode = Sv39;
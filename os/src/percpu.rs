@@ -0,0 +1,241 @@
+//! Per-hart data reached through `tp`
+//!
+//! Variables declared with [`percpu!`] are placed in the `.percpu` section,
+//! which is linked at address 0 so that a symbol's address is its offset in the
+//! section. Every hart gets its own copy of the section, and keeps the base of
+//! that copy in `tp`: the linker script leaves room for the boot hart's copy
+//! right after the template, the secondary harts get copies allocated by
+//! [`alloc_areas`].
+
+use alloc::vec::Vec;
+use core::{arch::asm, marker::PhantomData, ptr};
+
+use riscv::interrupt::supervisor as interrupt;
+
+use crate::mm::{allocator::FRAME_ALLOCATOR, PAGE_SIZE};
+
+/// Declare hart-local statics.
+///
+/// ```ignore
+/// percpu! {
+///     static NESTING: usize = 0;
+/// }
+/// NESTING.with(|nesting| *nesting += 1);
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)+) => {$(
+        $(#[$attr])*
+        $vis static $name: $crate::percpu::PerCpu<$ty> = {
+            #[link_section = ".percpu"]
+            static mut TEMPLATE: $ty = $init;
+
+            // the section is linked at 0, so the address is too far from any
+            // pc for `auipc` and has to be loaded as an absolute value
+            fn offset() -> usize {
+                let offset: usize;
+                unsafe {
+                    core::arch::asm!(
+                        "lui {0}, %hi({template})",
+                        "addi {0}, {0}, %lo({template})",
+                        out(reg) offset,
+                        template = sym TEMPLATE,
+                        options(pure, nomem, nostack),
+                    )
+                };
+                offset
+            }
+
+            unsafe { $crate::percpu::PerCpu::new(offset) }
+        };
+    )+};
+}
+
+/// A hart-local variable, declared with [`percpu!`].
+pub struct PerCpu<T> {
+    offset: fn() -> usize,
+    _marker: PhantomData<T>,
+}
+
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    /// # Safety
+    ///
+    /// `offset` must return the offset of a `T` in `.percpu`.
+    #[doc(hidden)]
+    pub const unsafe fn new(offset: fn() -> usize) -> PerCpu<T> {
+        PerCpu {
+            offset,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The calling hart's copy.
+    #[inline]
+    pub fn as_ptr(&self) -> *mut T {
+        (base() + (self.offset)()) as *mut T
+    }
+
+    /// Run `f` on the calling hart's copy, with interrupts disabled so that
+    /// no handler on this hart can get at it meanwhile.
+    #[inline]
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        interrupt::free(|| f(unsafe { &mut *self.as_ptr() }))
+    }
+
+    /// The copy of hart `hartid`, if it has one.
+    pub fn remote_ptr(&self, hartid: usize) -> Option<*mut T> {
+        area_of(hartid).map(|base| (base + (self.offset)()) as *mut T)
+    }
+}
+
+unsafe extern "C" {
+    static _percpu_start: u8;
+    static _percpu_load_end: u8;
+}
+
+/// Base of the `.percpu` copy of every hart brought up, by hart id.
+static AREAS: spin::Once<Vec<(usize, usize)>> = spin::Once::new();
+static BOOT_AREA: spin::Once<(usize, usize)> = spin::Once::new();
+
+/// Bytes of `.percpu`, up to the room left for the boot hart's copy.
+fn area_size() -> usize {
+    let size: usize;
+    unsafe {
+        asm!(
+            "lui {size}, %hi({end})",
+            "addi {size}, {size}, %lo({end})",
+            end = sym _percpu_load_end,
+            size = out(reg) size,
+            options(pure, nomem, nostack),
+        )
+    };
+    // `_percpu_load_start` is 0
+    size.next_multiple_of(64)
+}
+
+/// Whether the calling hart has its per-hart area yet. `tp` is cleared on
+/// entry so this holds before [`init_boot`] and [`init_secondary`].
+#[inline]
+pub fn ready() -> bool {
+    base() != 0
+}
+
+#[inline]
+fn base() -> usize {
+    let tp: usize;
+    unsafe { asm!("mv {}, tp", out(reg) tp, options(nomem, nostack)) };
+    tp
+}
+
+/// Set up the boot hart's copy of `.percpu`, in the kernel image, and point
+/// `tp` at it. Must run before any hart-local variable is used.
+pub fn init_boot(hartid: usize) {
+    let template = ptr::addr_of!(_percpu_start);
+    let size = area_size();
+    let area = unsafe { template.add(size) } as *mut u8;
+    unsafe { ptr::copy_nonoverlapping(template, area, size) };
+    BOOT_AREA.call_once(|| (hartid, area as usize));
+    set_tp(hartid, area as usize);
+}
+
+fn set_tp(hartid: usize, area: usize) {
+    unsafe { asm!("mv tp, {}", in(reg) area) };
+    CPU.with(|cpu| cpu.hart_id = hartid);
+}
+
+/// Copy `.percpu` for every hart in `harts` other than the boot hart.
+pub fn alloc_areas(harts: &[usize]) {
+    let template = ptr::addr_of!(_percpu_start);
+    let size = area_size();
+    let &(boot_hart, boot_area) = BOOT_AREA.get().expect("boot hart has no per-hart area");
+    AREAS.call_once(|| {
+        harts
+            .iter()
+            .map(|&hartid| {
+                if hartid == boot_hart {
+                    return (hartid, boot_area);
+                }
+                let frame = FRAME_ALLOCATOR
+                    .alloc_pages(size.div_ceil(PAGE_SIZE).max(1), 0)
+                    .expect("failed to allocate per-hart area");
+                let area = frame.virt_addr().as_ptr();
+                unsafe { ptr::copy_nonoverlapping(template, area, size) };
+                frame.leak();
+                (hartid, area as usize)
+            })
+            .collect()
+    });
+}
+
+/// Point `tp` of a secondary hart at its area from [`alloc_areas`].
+pub fn init_secondary(hartid: usize) {
+    let area = area_of(hartid).expect("hart has no per-hart area");
+    set_tp(hartid, area);
+}
+
+fn area_of(hartid: usize) -> Option<usize> {
+    AREAS
+        .get()
+        .and_then(|areas| areas.iter().find(|&&(id, _)| id == hartid))
+        .or(BOOT_AREA.get().filter(|&&(id, _)| id == hartid))
+        .map(|&(_, area)| area)
+}
+
+/// State every hart keeps about itself.
+#[derive(Debug)]
+pub struct Cpu {
+    pub hart_id: usize,
+    /// Traps being handled on this hart, nested ones included.
+    pub trap_depth: usize,
+    /// Task running on this hart, none until there is a scheduler.
+    pub current_task: usize,
+    /// When the timer of this hart fires next, in ticks.
+    pub timer_deadline: u64,
+    /// Free for the trap entry to stash a register in.
+    pub trap_scratch: usize,
+}
+
+percpu! {
+    /// The calling hart's [`Cpu`].
+    pub static CPU: Cpu = Cpu {
+        hart_id: 0,
+        trap_depth: 0,
+        current_task: 0,
+        timer_deadline: 0,
+        trap_scratch: 0,
+    };
+}
+
+/// Id of the calling hart.
+#[inline]
+pub fn hart_id() -> usize {
+    CPU.with(|cpu| cpu.hart_id)
+}
+
+/// Task running on the calling hart, 0 for none.
+#[inline]
+pub fn current_task() -> usize {
+    CPU.with(|cpu| cpu.current_task)
+}
+
+/// Record the task the calling hart switches to.
+#[inline]
+#[allow(dead_code)] // no scheduler switches tasks yet
+pub fn set_current_task(task: usize) {
+    CPU.with(|cpu| cpu.current_task = task)
+}
+
+/// What the trap entry stashed on the calling hart.
+#[inline]
+#[allow(dead_code)] // the trap entry saves everything on the stack so far
+pub fn trap_scratch() -> usize {
+    CPU.with(|cpu| cpu.trap_scratch)
+}
+
+#[inline]
+#[allow(dead_code)] // the trap entry saves everything on the stack so far
+pub fn set_trap_scratch(value: usize) {
+    CPU.with(|cpu| cpu.trap_scratch = value)
+}
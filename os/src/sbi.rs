@@ -1,6 +1,6 @@
 //! SBI call wrappers
 
-use sbi_rt::{NoReason, Shutdown};
+use sbi_rt::{ColdReboot, NoReason, Shutdown};
 
 /// use sbi call to shutdown the kernel
 pub fn shutdown() -> ! {
@@ -8,3 +8,9 @@ pub fn shutdown() -> ! {
     // crate::board::QEMU_EXIT_HANDLE.exit_failure();
     unreachable!()
 }
+
+/// use sbi call to reset the machine
+pub fn reboot() -> ! {
+    sbi_rt::system_reset(ColdReboot, NoReason);
+    unreachable!()
+}
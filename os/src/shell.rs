@@ -0,0 +1,684 @@
+//! Kernel monitor over the console
+//!
+//! Reads commands with line editing and history, for looking around a
+//! running system: `help` lists the commands.
+
+use alloc::{collections::VecDeque, string::String, sync::Arc, vec, vec::Vec};
+use core::str;
+
+use riscv::interrupt::supervisor as interrupt;
+use spin::Mutex;
+
+use crate::{
+    console, dmesg, drivers, dt,
+    fs::{self, StorageDevice},
+    ipi, logging,
+    mm::{allocator::FRAME_ALLOCATOR, PageTableEntryFlags, PhysAddr, VirtAddr, PAGE_SIZE},
+    percpu::{self, CPU},
+    sbi,
+    time::{self, Instant},
+};
+
+const PROMPT: &str = "> ";
+const HISTORY_SIZE: usize = 32;
+/// Bytes `cat` reads at a time.
+const CAT_CHUNK_SIZE: usize = 4096;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    run: fn(&[&str]),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help                 list the commands",
+        run: help,
+    },
+    Command {
+        name: "meminfo",
+        usage: "meminfo              frame and heap usage",
+        run: meminfo,
+    },
+    Command {
+        name: "pt",
+        usage: "pt dump <va>         walk the kernel page table for <va>",
+        run: pt,
+    },
+    Command {
+        name: "harts",
+        usage: "harts                state of every hart",
+        run: harts,
+    },
+    Command {
+        name: "dt",
+        usage: "dt [path]            the device tree, or the properties of a node",
+        run: dt,
+    },
+    Command {
+        name: "blk",
+        usage: "blk [<cmd> ...]      list block devices, `blk help` for the rest",
+        run: blk,
+    },
+    Command {
+        name: "sync",
+        usage: "sync                 write cached blocks back",
+        run: sync,
+    },
+    Command {
+        name: "ls",
+        usage: "ls [path]            list a directory",
+        run: ls,
+    },
+    Command {
+        name: "cat",
+        usage: "cat <path>           print a file",
+        run: cat,
+    },
+    Command {
+        name: "log",
+        usage: "log [level|filter x] show or change what is logged",
+        run: log,
+    },
+    Command {
+        name: "dmesg",
+        usage: "dmesg [-c]           print the kernel log, -c to clear it after",
+        run: dmesg,
+    },
+    Command {
+        name: "uptime",
+        usage: "uptime               time since boot",
+        run: uptime,
+    },
+    Command {
+        name: "date",
+        usage: "date                 wall-clock time, from the RTC",
+        run: date,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot               reset the machine",
+        run: reboot,
+    },
+    Command {
+        name: "shutdown",
+        usage: "shutdown             power the machine off",
+        run: shutdown,
+    },
+];
+
+/// Run commands read from the console, forever.
+pub fn run() -> ! {
+    let mut editor = LineEditor::new();
+    println!("[kernel] monitor ready, type `help` for commands");
+    loop {
+        let line = editor.read_line(PROMPT);
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some(&name) = args.first() else {
+            continue;
+        };
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => (command.run)(&args[1..]),
+            None => println!("{}: command not found", name),
+        }
+    }
+}
+
+/// Keys the line editor reacts to, once escape sequences are decoded.
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    /// Ctrl-U
+    KillLine,
+    /// Ctrl-C
+    Cancel,
+    Ignored,
+}
+
+fn read_key() -> Key {
+    match console::getchar() {
+        b'\r' | b'\n' => Key::Enter,
+        0x08 | 0x7f => Key::Backspace,
+        0x01 => Key::Home,
+        0x05 => Key::End,
+        0x02 => Key::Left,
+        0x06 => Key::Right,
+        0x10 => Key::Up,
+        0x0e => Key::Down,
+        0x15 => Key::KillLine,
+        0x03 => Key::Cancel,
+        0x1b => read_escape(),
+        byte @ 0x20..0x7f => Key::Char(byte as char),
+        _ => Key::Ignored,
+    }
+}
+
+/// Decode a CSI or SS3 sequence, the escape being read already.
+fn read_escape() -> Key {
+    if !matches!(console::getchar(), b'[' | b'O') {
+        return Key::Ignored;
+    }
+    match console::getchar() {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        b'H' => Key::Home,
+        b'F' => Key::End,
+        // `ESC [ n ~`
+        digit @ b'0'..=b'9' => {
+            let mut number = (digit - b'0') as u32;
+            loop {
+                match console::getchar() {
+                    b'~' => break,
+                    digit @ b'0'..=b'9' => number = number * 10 + (digit - b'0') as u32,
+                    _ => return Key::Ignored,
+                }
+            }
+            match number {
+                1 | 7 => Key::Home,
+                3 => Key::Delete,
+                4 | 8 => Key::End,
+                _ => Key::Ignored,
+            }
+        }
+        _ => Key::Ignored,
+    }
+}
+
+struct LineEditor {
+    history: VecDeque<String>,
+}
+
+impl LineEditor {
+    fn new() -> LineEditor {
+        LineEditor {
+            history: VecDeque::new(),
+        }
+    }
+
+    fn read_line(&mut self, prompt: &str) -> String {
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // `history.len()` stands for the line being typed
+        let mut selected = self.history.len();
+        let mut typed: Vec<char> = Vec::new();
+        print!("{}", prompt);
+        loop {
+            match read_key() {
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Enter => break,
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::Delete if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::Left => cursor = cursor.saturating_sub(1),
+                Key::Right => cursor = (cursor + 1).min(line.len()),
+                Key::Home => cursor = 0,
+                Key::End => cursor = line.len(),
+                Key::Up if selected > 0 => {
+                    if selected == self.history.len() {
+                        typed = line.clone();
+                    }
+                    selected -= 1;
+                    line = self.history[selected].chars().collect();
+                    cursor = line.len();
+                }
+                Key::Down if selected < self.history.len() => {
+                    selected += 1;
+                    line = match self.history.get(selected) {
+                        Some(entry) => entry.chars().collect(),
+                        None => typed.clone(),
+                    };
+                    cursor = line.len();
+                }
+                Key::KillLine => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                Key::Cancel => {
+                    println!("^C");
+                    line.clear();
+                    cursor = 0;
+                    selected = self.history.len();
+                    print!("{}", prompt);
+                    continue;
+                }
+                _ => continue,
+            }
+            redraw(prompt, &line, cursor);
+        }
+        println!("");
+        let line: String = line.into_iter().collect();
+        if !line.trim().is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+}
+
+/// Rewrite the whole line and put the terminal cursor at `cursor`.
+fn redraw(prompt: &str, line: &[char], cursor: usize) {
+    let text: String = line.iter().collect();
+    print!("\r\x1b[K{}{}", prompt, text);
+    if cursor < line.len() {
+        print!("\x1b[{}D", line.len() - cursor);
+    }
+}
+
+fn help(_: &[&str]) {
+    for command in COMMANDS {
+        println!("  {}", command.usage);
+    }
+}
+
+fn meminfo(_: &[&str]) {
+    let mut total = 0;
+    let mut free = 0;
+    for (i, zone) in FRAME_ALLOCATOR.stats().iter().enumerate() {
+        println!(
+            "zone {} [{:#x}, {:#x}): {} of {} frames free",
+            i, zone.start, zone.end, zone.free_frames, zone.total_frames
+        );
+        total += zone.total_frames;
+        free += zone.free_frames;
+    }
+    println!(
+        "frames: {} KiB total, {} KiB used, {} KiB free",
+        total * PAGE_SIZE / 1024,
+        (total - free) * PAGE_SIZE / 1024,
+        free * PAGE_SIZE / 1024
+    );
+    let (heap_total, heap_user, heap_actual) = interrupt::free(|| {
+        let heap = crate::KERNEL_HEAP.0.lock();
+        (
+            heap.stats_total_bytes(),
+            heap.stats_alloc_user(),
+            heap.stats_alloc_actual(),
+        )
+    });
+    println!(
+        "heap: {} KiB total, {} bytes requested, {} bytes allocated",
+        heap_total / 1024,
+        heap_user,
+        heap_actual
+    );
+}
+
+fn parse_usize(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn pt(args: &[&str]) {
+    let (Some(&"dump"), Some(va)) = (args.first(), args.get(1).and_then(|s| parse_usize(s))) else {
+        println!("usage: pt dump <va>");
+        return;
+    };
+    let Some(table) = crate::KERNEL_PAGE_TABLE.get() else {
+        println!("no kernel page table");
+        return;
+    };
+    let va = VirtAddr::new(va);
+    for (level, pte) in table.walk(va) {
+        let flags = pte.flags();
+        println!(
+            "level {} entry {:3}: {:#018x} ppn {:#x} {:?}",
+            level,
+            va.vpn(level),
+            PhysAddr::from_page_number(pte.full_ppn()).as_usize(),
+            pte.full_ppn(),
+            flags
+        );
+        if !flags.contains(PageTableEntryFlags::V) {
+            println!("not mapped");
+        }
+    }
+    if let Some(pa) = table.translate(va) {
+        println!("{:?} -> {:?}", va, pa);
+    }
+}
+
+fn harts(_: &[&str]) {
+    let this = percpu::hart_id();
+    let states = Arc::new(Mutex::new(Vec::new()));
+    let collect = states.clone();
+    // every hart reports on itself, the others from the IPI, which is not
+    // one of their own traps
+    let report = move || {
+        let state = CPU.with(|cpu| {
+            (
+                cpu.hart_id,
+                cpu.trap_depth - usize::from(cpu.hart_id != this),
+                cpu.current_task,
+                cpu.timer_deadline,
+            )
+        });
+        collect.lock().push(state);
+    };
+    report();
+    ipi::run_on_others(report);
+    let mut states = states.lock();
+    states.sort_unstable();
+    for &(hartid, trap_depth, task, deadline) in states.iter() {
+        println!(
+            "hart {}{}: trap depth {}, task {}, next timer at {:?}",
+            hartid,
+            if hartid == this { " (this)" } else { "" },
+            trap_depth,
+            task,
+            Instant::from_ticks(deadline)
+        );
+    }
+}
+
+fn dt(args: &[&str]) {
+    let tree = dt::tree();
+    match args.first() {
+        None => print_node(tree.root(), 0),
+        Some(path) => match tree.find_node(path) {
+            Some(node) => {
+                for property in node.properties() {
+                    println!("{} = {}", property.name(), PropertyValue(property));
+                }
+                for child in node.children() {
+                    println!("{}/", child.name());
+                }
+            }
+            None => println!("dt: {}: no such node", path),
+        },
+    }
+}
+
+fn print_node(node: dt::Node<'_>, depth: usize) {
+    let name = if depth == 0 { "/" } else { node.name() };
+    let compatible = node.compatible().next().unwrap_or("");
+    println!("{:indent$}{} {}", "", name, compatible, indent = depth * 2);
+    for child in node.children() {
+        print_node(child, depth + 1);
+    }
+}
+
+/// A property value, as strings when it looks like some and as cells otherwise.
+struct PropertyValue<'a>(&'a dt::Property);
+
+impl core::fmt::Display for PropertyValue<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let value = self.0.value();
+        let printable = value.last() == Some(&0)
+            && value[0] != 0
+            && value.iter().all(|&b| b == 0 || (0x20..0x7f).contains(&b));
+        if printable {
+            let strings: Vec<_> = self.0.as_str_list().collect();
+            return write!(f, "{:?}", strings);
+        }
+        if value.len().is_multiple_of(4) {
+            write!(f, "<")?;
+            for (i, cell) in self.0.cells().enumerate() {
+                write!(f, "{}{:#x}", if i == 0 { "" } else { " " }, cell)?;
+            }
+            return write!(f, ">");
+        }
+        write!(f, "{:02x?}", value)
+    }
+}
+
+fn blk(args: &[&str]) {
+    let devices = fs::virtio_blk::devices();
+    match args {
+        [] => {
+            for (i, device) in devices.iter().enumerate() {
+                println!("{}: virtio, {} sectors", i, device.capacity());
+            }
+            for (i, cache) in fs::cache::caches().iter().enumerate() {
+                println!("cache {}: {}", i, cache.stats());
+            }
+        }
+        ["read", dev, sector] => {
+            let (Some(device), Some(sector)) = (
+                dev.parse::<usize>().ok().and_then(|dev| devices.get(dev)),
+                parse_usize(sector),
+            ) else {
+                println!("blk: no such device or bad sector number");
+                return;
+            };
+            let mut buf = [0u8; 512];
+            match device.read_block(sector as u64, &mut buf) {
+                Ok(()) => hexdump(&buf),
+                Err(e) => println!("blk: {}", e),
+            }
+        }
+        ["discard", dev, start, count] => {
+            let (Some(device), Some(start), Some(count)) = (
+                dev.parse::<usize>().ok().and_then(|dev| devices.get(dev)),
+                parse_usize(start),
+                parse_usize(count),
+            ) else {
+                println!("blk: no such device or bad sector numbers");
+                return;
+            };
+            let sectors = start as u64..(start + count) as u64;
+            if let Err(e) = device.discard(sectors) {
+                println!("blk: {}", e);
+            }
+        }
+        // goes through the cache, so that `sync` or the write-back timer
+        // writes it out
+        ["fill", cache, block, byte] => {
+            let caches = fs::cache::caches();
+            let (Some(cache), Some(block), Some(byte)) = (
+                cache
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|cache| caches.get(cache)),
+                parse_usize(block),
+                parse_usize(byte).and_then(|byte| u8::try_from(byte).ok()),
+            ) else {
+                println!("blk: no such cache, or bad block number or byte");
+                return;
+            };
+            match cache.get(block as u64) {
+                Ok(buffer) => buffer.write().fill(byte),
+                Err(e) => println!("blk: {}", e),
+            }
+        }
+        _ => {
+            println!("usage: blk");
+            println!("       blk read <dev> <sector>");
+            println!("       blk discard <dev> <start> <count>");
+            println!("       blk fill <cache> <block> <byte>");
+        }
+    }
+}
+
+fn sync(_args: &[&str]) {
+    if let Err(e) = fs::cache::sync_all() {
+        println!("sync: {}", e);
+    }
+}
+
+fn hexdump(data: &[u8]) {
+    for (i, line) in data.chunks(16).enumerate() {
+        print!("{:08x}  ", i * 16);
+        for byte in line {
+            print!("{:02x} ", byte);
+        }
+        let text: String = line
+            .iter()
+            .map(|&b| {
+                if (0x20..0x7f).contains(&b) {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        println!(" {}", text);
+    }
+}
+
+fn ls(args: &[&str]) {
+    let Some(root) = fs::root() else {
+        println!("ls: no file system mounted");
+        return;
+    };
+    let path = args.first().copied().unwrap_or("/");
+    match root.read_dir(path) {
+        Ok(entries) => {
+            for entry in entries {
+                if entry.is_dir {
+                    println!("{:>10}  {}/", "", entry.name);
+                } else {
+                    println!("{:>10}  {}", entry.size, entry.name);
+                }
+            }
+        }
+        Err(e) => println!("ls: {}: {}", path, e),
+    }
+}
+
+fn cat(args: &[&str]) {
+    let Some(root) = fs::root() else {
+        println!("cat: no file system mounted");
+        return;
+    };
+    let Some(&path) = args.first() else {
+        println!("usage: cat <path>");
+        return;
+    };
+    let mut buf = vec![0; CAT_CHUNK_SIZE];
+    let mut offset = 0;
+    // bytes of a character cut off by the end of the last chunk, kept at the
+    // start of `buf`
+    let mut carry = 0;
+    loop {
+        let len = match root.read_at(path, offset, &mut buf[carry..]) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) => {
+                println!("cat: {}: {}", path, e);
+                return;
+            }
+        };
+        offset += len as u64;
+        let len = carry + len;
+        let valid = match str::from_utf8(&buf[..len]) {
+            Ok(_) => len,
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => {
+                let at = offset - (len - e.valid_up_to()) as u64;
+                println!("cat: {}: binary file, not valid UTF-8 at byte {}", path, at);
+                return;
+            }
+        };
+        print!("{}", str::from_utf8(&buf[..valid]).unwrap());
+        buf.copy_within(valid..len, 0);
+        carry = len - valid;
+    }
+}
+
+fn log(args: &[&str]) {
+    let result = match args {
+        [] => {
+            println!("{}", logging::spec());
+            Ok(())
+        }
+        ["level"] => {
+            println!("{}", log::max_level());
+            Ok(())
+        }
+        ["level", level] => logging::configure(level),
+        ["filter", spec] => logging::configure(spec),
+        _ => {
+            println!(
+                "usage: log [level <off|error|warn|info|debug|trace> | filter <target=level,...>]"
+            );
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        println!("log: {}", e);
+    }
+}
+
+fn dmesg(args: &[&str]) {
+    dmesg::dump();
+    if args == ["-c"] {
+        dmesg::clear();
+    }
+}
+
+fn uptime(_: &[&str]) {
+    let since_boot = Instant::now().since_boot();
+    println!(
+        "{}.{:03} s, {} ticks",
+        since_boot.as_secs(),
+        since_boot.subsec_millis(),
+        time::jiffies()
+    );
+}
+
+fn date(_: &[&str]) {
+    let Some(now) = drivers::rtc::now() else {
+        println!("no real-time clock");
+        return;
+    };
+    let secs = now.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    println!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    );
+}
+
+/// Gregorian `(year, month, day)` of the day `days` after 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // shift the epoch to 0000-03-01, so that leap days end a 400-year era
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn reboot(_: &[&str]) {
+    drivers::syscon::reboot();
+    sbi::reboot();
+}
+
+fn shutdown(_: &[&str]) {
+    drivers::syscon::poweroff();
+    sbi::shutdown();
+}
@@ -0,0 +1,245 @@
+//! Monotonic clock and supervisor timer interrupts
+//!
+//! Time is counted in ticks of the `time` CSR, which runs at the
+//! `timebase-frequency` of the device tree. Every hart gets a timer interrupt
+//! [`TICKS_PER_SEC`] times a second, or earlier when a one-shot timer added
+//! with [`add_timer`] is due.
+
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::{
+    arch::asm,
+    fmt,
+    ops::{Add, AddAssign, Sub},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use riscv::{
+    interrupt::supervisor as interrupt,
+    register::{sie, time},
+};
+use spin::Mutex;
+
+use crate::percpu::CPU;
+
+pub use core::time::Duration;
+
+/// Periodic timer interrupts per second.
+pub const TICKS_PER_SEC: u64 = 100;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// `stimecmp`, provided by the Sstc extension
+const CSR_STIMECMP: usize = 0x14d;
+
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static SSTC: AtomicBool = AtomicBool::new(false);
+/// Periodic timer interrupts taken so far, on all harts.
+static JIFFIES: AtomicU64 = AtomicU64::new(0);
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+/// Pending one-shot timers, ordered by deadline then by id.
+static TIMERS: Mutex<BTreeMap<(u64, u64), Callback>> = Mutex::new(BTreeMap::new());
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Record the clock frequency and start the periodic timer interrupt on the
+/// boot hart. `sstc` is whether every hart has the Sstc extension, in which
+/// case `stimecmp` is written directly instead of going through SBI.
+pub fn init(frequency: u64, sstc: bool) {
+    assert!(
+        frequency != 0,
+        "timebase-frequency missing from device tree"
+    );
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    SSTC.store(sstc, Ordering::Relaxed);
+    init_hart();
+}
+
+/// Start the periodic timer interrupt on the calling hart.
+pub fn init_hart() {
+    program_next(Instant::now().ticks());
+    unsafe { sie::set_stimer() };
+}
+
+/// Clock frequency in Hz.
+#[inline]
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Periodic timer interrupts taken since boot, on all harts.
+pub fn jiffies() -> u64 {
+    JIFFIES.load(Ordering::Relaxed)
+}
+
+/// A point in time, as read from the `time` CSR.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    #[inline]
+    pub fn now() -> Instant {
+        Instant(time::read64())
+    }
+
+    #[inline]
+    pub const fn from_ticks(ticks: u64) -> Instant {
+        Instant(ticks)
+    }
+
+    #[inline]
+    pub const fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// Time since boot.
+    pub fn since_boot(&self) -> Duration {
+        ticks_to_duration(self.0)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Time from `earlier` to `self`, zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_ticks(duration)).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_sub(duration_to_ticks(duration)).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        self.checked_add(rhs)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        self.checked_sub(rhs)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl fmt::Debug for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_boot = self.since_boot();
+        write!(
+            f,
+            "{}.{:06}",
+            since_boot.as_secs(),
+            since_boot.subsec_micros()
+        )
+    }
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * NANOS_PER_SEC / frequency() as u128;
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+fn duration_to_ticks(duration: Duration) -> u64 {
+    (duration.as_nanos() * frequency() as u128 / NANOS_PER_SEC) as u64
+}
+
+/// Handle to a timer added with [`add_timer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64, u64);
+
+/// Run `callback` once at `deadline`, from the timer interrupt of whichever
+/// hart sees it due first. Callbacks run with interrupts disabled.
+pub fn add_timer(deadline: Instant, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    let id = TimerId(deadline.0, NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+    let earliest = interrupt::free(|| {
+        let mut timers = TIMERS.lock();
+        timers.insert((id.0, id.1), Box::new(callback));
+        timers.first_key_value().map(|(&(deadline, _), _)| deadline)
+    });
+    if earliest == Some(id.0) {
+        program_next(Instant::now().ticks());
+    }
+    id
+}
+
+/// Run `callback` once after `delay`.
+pub fn add_timer_after(delay: Duration, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    add_timer(Instant::now() + delay, callback)
+}
+
+/// Remove a timer which has not fired yet. Returns whether it was pending.
+pub fn cancel_timer(id: TimerId) -> bool {
+    interrupt::free(|| TIMERS.lock().remove(&(id.0, id.1)).is_some())
+}
+
+/// Supervisor timer interrupt: run the due timers and program the next
+/// interrupt.
+pub fn handle_interrupt() {
+    let now = Instant::now().ticks();
+    JIFFIES.fetch_add(1, Ordering::Relaxed);
+    loop {
+        // the lock is not held while a callback runs, which may add timers
+        let due = {
+            let mut timers = TIMERS.lock();
+            match timers.first_entry() {
+                Some(entry) if entry.key().0 <= now => Some(entry.remove()),
+                _ => None,
+            }
+        };
+        match due {
+            Some(callback) => callback(),
+            None => break,
+        }
+    }
+    program_next(now);
+}
+
+/// Set the timer of the calling hart to the next periodic tick after `now`,
+/// or to the earliest pending timer when that comes first.
+fn program_next(now: u64) {
+    let tick = now + frequency() / TICKS_PER_SEC;
+    let next = interrupt::free(|| {
+        TIMERS
+            .lock()
+            .first_key_value()
+            .map_or(tick, |(&(deadline, _), _)| deadline.min(tick))
+    });
+    set_timer(next);
+}
+
+fn set_timer(deadline: u64) {
+    CPU.with(|cpu| cpu.timer_deadline = deadline);
+    if SSTC.load(Ordering::Relaxed) {
+        unsafe { asm!("csrw {csr}, {0}", in(reg) deadline, csr = const CSR_STIMECMP) };
+    } else {
+        sbi_rt::set_timer(deadline);
+    }
+}
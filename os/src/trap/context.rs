@@ -0,0 +1,61 @@
+//! Register state saved on trap entry
+
+use riscv::register::scause::{Exception, Interrupt, Trap};
+
+/// Everything `__trap_entry` pushes on the stack, in push order. The handler
+/// may change `sepc` and the registers, which are restored by `sret`.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct TrapContext {
+    /// general purpose registers `x0` to `x31`, `x2` being `sp` before the trap
+    pub x: [usize; 32],
+    pub sstatus: usize,
+    pub sepc: usize,
+    pub scause: usize,
+    pub stval: usize,
+}
+
+/// ABI names of `x0` to `x31`.
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// `SPP`: the trap came from supervisor mode
+const SSTATUS_SPP: usize = 1 << 8;
+
+impl TrapContext {
+    /// Number of bytes `__trap_entry` reserves on the stack.
+    pub const SIZE: usize = core::mem::size_of::<TrapContext>();
+
+    pub fn cause(&self) -> Trap {
+        let code = self.scause & !(1 << (usize::BITS - 1));
+        if self.scause & (1 << (usize::BITS - 1)) != 0 {
+            Trap::Interrupt(Interrupt::from(code))
+        } else {
+            Trap::Exception(Exception::from(code))
+        }
+    }
+
+    /// Whether the trap was taken from supervisor mode.
+    pub fn in_supervisor(&self) -> bool {
+        self.sstatus & SSTATUS_SPP != 0
+    }
+
+    /// Print every register, four per line.
+    pub fn dump(&self) {
+        for i in (0..32).step_by(4) {
+            let reg = |n: usize| (REGISTER_NAMES[i + n], self.x[i + n]);
+            let [(n0, r0), (n1, r1), (n2, r2), (n3, r3)] = [reg(0), reg(1), reg(2), reg(3)];
+            println!(
+                "{:>4}: {:#018x}  {:>4}: {:#018x}  {:>4}: {:#018x}  {:>4}: {:#018x}",
+                n0, r0, n1, r1, n2, r2, n3, r3
+            );
+        }
+        println!(
+            "sstatus: {:#018x}  sepc: {:#018x}  scause: {:#018x}  stval: {:#018x}",
+            self.sstatus, self.sepc, self.scause, self.stval
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Supervisor trap handling
+//!
+//! [`init`] points `stvec` at `__trap_entry`, which saves a [`TrapContext`]
+//! on the current stack and hands it to [`trap_handler`]. The kernel has no
+//! user mode yet, so every trap comes from supervisor mode and a fault is
+//! fatal: it is reported with the faulting PC, address and instruction, then
+//! the kernel panics.
+
+mod context;
+
+pub use context::TrapContext;
+
+use core::arch::global_asm;
+
+use log::*;
+
+use crate::percpu::{self, CPU};
+use riscv::register::{
+    scause::{Exception, Interrupt, Trap},
+    stvec::{self, TrapMode},
+};
+
+global_asm!(
+    r"
+    .section .text.trap
+    .globl __trap_entry
+    .p2align 2
+__trap_entry:
+    addi    sp, sp, -{size}
+    sd      x1, 1*8(sp)
+    .irp    n, 3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+    sd      x\n, \n*8(sp)
+    .endr
+    addi    t0, sp, {size}          // sp before the trap
+    sd      t0, 2*8(sp)
+    csrr    t0, sstatus
+    csrr    t1, sepc
+    csrr    t2, scause
+    csrr    t3, stval
+    sd      t0, 32*8(sp)
+    sd      t1, 33*8(sp)
+    sd      t2, 34*8(sp)
+    sd      t3, 35*8(sp)
+
+    mv      a0, sp
+    call    {handler}
+
+    ld      t0, 32*8(sp)
+    ld      t1, 33*8(sp)
+    csrw    sstatus, t0
+    csrw    sepc, t1
+    ld      x1, 1*8(sp)
+    .irp    n, 3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31
+    ld      x\n, \n*8(sp)
+    .endr
+    addi    sp, sp, {size}
+    sret
+    ",
+    size = const TrapContext::SIZE,
+    handler = sym trap_handler,
+);
+
+unsafe extern "C" {
+    fn __trap_entry();
+}
+
+/// Install the trap vector on the calling hart.
+pub fn init() {
+    unsafe { stvec::write(__trap_entry as *const () as usize, TrapMode::Direct) };
+}
+
+extern "C" fn trap_handler(ctx: &mut TrapContext) {
+    CPU.with(|cpu| cpu.trap_depth += 1);
+    match ctx.cause() {
+        Trap::Exception(Exception::Breakpoint) => {
+            warn!("[kernel] breakpoint at {:#x}", ctx.sepc);
+            ctx.sepc += instruction_len(ctx.sepc);
+        }
+        Trap::Exception(exception) => fatal(ctx, exception),
+        Trap::Interrupt(Interrupt::SupervisorTimer) => crate::time::handle_interrupt(),
+        Trap::Interrupt(Interrupt::SupervisorSoft) => crate::ipi::handle_interrupt(),
+        Trap::Interrupt(Interrupt::SupervisorExternal) => crate::drivers::plic::handle_interrupt(),
+        Trap::Interrupt(Interrupt::Unknown) => {
+            panic!("unknown interrupt, scause = {:#x}", ctx.scause)
+        }
+    }
+    CPU.with(|cpu| cpu.trap_depth -= 1);
+}
+
+/// Report an exception the kernel cannot recover from, then panic.
+fn fatal(ctx: &TrapContext, exception: Exception) -> ! {
+    let mode = if ctx.in_supervisor() { "S" } else { "U" };
+    let cpu = CPU.with(|cpu| (cpu.hart_id, cpu.trap_depth));
+    println!(
+        "[kernel] {:?} in {}-mode at pc = {:#x} on hart {}, trap depth {}, task {}",
+        exception,
+        mode,
+        ctx.sepc,
+        cpu.0,
+        cpu.1,
+        percpu::current_task()
+    );
+    match exception {
+        Exception::InstructionPageFault
+        | Exception::LoadPageFault
+        | Exception::StorePageFault
+        | Exception::InstructionFault
+        | Exception::LoadFault
+        | Exception::StoreFault
+        | Exception::InstructionMisaligned
+        | Exception::LoadMisaligned
+        | Exception::StoreMisaligned => {
+            println!("[kernel] faulting address {:#x}", ctx.stval);
+        }
+        Exception::IllegalInstruction if ctx.stval != 0 => {
+            println!("[kernel] illegal instruction {:#010x}", ctx.stval);
+        }
+        _ => {}
+    }
+    // fetching from `sepc` would trap again
+    if !matches!(
+        exception,
+        Exception::InstructionPageFault
+            | Exception::InstructionFault
+            | Exception::InstructionMisaligned
+    ) {
+        let len = instruction_len(ctx.sepc);
+        let insn = if len == 2 {
+            read_parcel(ctx.sepc) as u32
+        } else {
+            read_parcel(ctx.sepc) as u32 | (read_parcel(ctx.sepc + 2) as u32) << 16
+        };
+        println!(
+            "[kernel] instruction at pc: {:#0width$x}",
+            insn,
+            width = len * 2 + 2
+        );
+    }
+    ctx.dump();
+    panic!("unhandled {:?} at {:#x}", exception, ctx.sepc);
+}
+
+/// Length of the instruction at `pc`: 2 when compressed, 4 otherwise.
+fn instruction_len(pc: usize) -> usize {
+    if read_parcel(pc) & 0b11 == 0b11 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Instructions are only 2-byte aligned with the C extension, so they are read
+/// in 16-bit parcels.
+fn read_parcel(addr: usize) -> u16 {
+    unsafe { (addr as *const u16).read_volatile() }
+}